@@ -1,14 +1,15 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
+    ffi::OsString,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
     net::TcpStream,
 };
 
-use clap::{arg, command, value_parser, Command};
-use kvs::{transport::Response, KvsError, Result};
-use kvs_protocol::request::Request;
-use kvs_protocol::serializer::serialize;
+use clap::{arg, command, value_parser, ArgMatches, Command};
+use kvs::{
+    transport::{self, Response},
+    Condition, KvsError, Result, WriteBatch,
+};
 use log::debug;
-use serde::Deserialize;
 
 fn main() -> Result<()> {
     env_logger::init();
@@ -36,14 +37,33 @@ fn main() -> Result<()> {
                         .help("A string key")
                         .id("key")
                         .required(true)
-                        .value_parser(value_parser!(String)),
+                        .value_parser(value_parser!(OsString)),
                 )
                 .arg(
                     arg!(<VALUE>)
                         .help("A string value")
                         .id("val")
                         .required(true)
-                        .value_parser(value_parser!(String)),
+                        .value_parser(value_parser!(OsString)),
+                )
+                .arg(
+                    arg!(--ttl <SECONDS> "Expire the key after the given seconds")
+                        .required(false)
+                        .conflicts_with_all(["if-absent", "if-present", "if-equals"])
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--"if-absent" "Set the key only if it does not exist")
+                        .conflicts_with_all(["if-present", "if-equals"]),
+                )
+                .arg(
+                    arg!(--"if-present" "Set the key only if it exists")
+                        .conflicts_with("if-equals"),
+                )
+                .arg(
+                    arg!(--"if-equals" <EXPECTED> "Set the key only if its value is EXPECTED")
+                        .required(false)
+                        .value_parser(value_parser!(OsString)),
                 ),
         )
         .subcommand(
@@ -54,7 +74,7 @@ fn main() -> Result<()> {
                         .help("A string key to fetch from in-memory db")
                         .id("key")
                         .required(true)
-                        .value_parser(value_parser!(String)),
+                        .value_parser(value_parser!(OsString)),
                 ),
         )
         .subcommand(
@@ -63,9 +83,49 @@ fn main() -> Result<()> {
                     .help("A string key to delete from in-memory db")
                     .id("key")
                     .required(true)
-                    .value_parser(value_parser!(String)),
+                    .value_parser(value_parser!(OsString)),
             ),
         )
+        .subcommand(
+            Command::new("ttl")
+                .about("Get the remaining time to live of a key in seconds, -1 if it never expires")
+                .arg(
+                    arg!(<KEY>)
+                        .help("A string key")
+                        .id("key")
+                        .required(true)
+                        .value_parser(value_parser!(OsString)),
+                ),
+        )
+        .subcommand(
+            Command::new("persist")
+                .about("Remove the expiry of a key")
+                .arg(
+                    arg!(<KEY>)
+                        .help("A string key")
+                        .id("key")
+                        .required(true)
+                        .value_parser(value_parser!(OsString)),
+                ),
+        )
+        .subcommand(
+            Command::new("batch")
+                .about("Apply multiple writes atomically")
+                .arg(
+                    arg!(<OPS>)
+                        .help(
+                            "Writes of the batch, each is either `set <KEY> <VALUE>` or `rm <KEY>`",
+                        )
+                        .id("ops")
+                        .required(true)
+                        .num_args(1..)
+                        .value_parser(value_parser!(OsString)),
+                ),
+        )
+        .subcommand(Command::new("multi").about(
+            "Run a transaction of the `get <KEY>`, `set <KEY> <VALUE>` and `rm <KEY>` lines \
+             read from stdin, committed by `exec` or at the end of the input, or aborted by `discard`",
+        ))
         .get_matches();
 
     let ip = matches.get_one::<String>("ip").unwrap();
@@ -79,49 +139,88 @@ fn main() -> Result<()> {
 
     match matches.subcommand() {
         Some(("set", sub_m)) => {
-            let key = sub_m.get_one::<String>("key").unwrap();
-            let val = sub_m.get_one::<String>("val").unwrap();
+            let key = arg_bytes(sub_m, "key");
+            let value = arg_bytes(sub_m, "val");
 
-            let serialized_cmd = serialize(&Request::Set {
-                key: key.to_string(),
-                val: val.to_string(),
-            });
-
-            request_writer.write_all(serialized_cmd.as_bytes())?;
-            request_writer.flush()?;
+            let cmd = if let Some(ttl) = sub_m.get_one::<u64>("ttl") {
+                transport::Command::SetWithTtl {
+                    key,
+                    value,
+                    ttl_ms: ttl.saturating_mul(1000),
+                }
+            } else if let Some(condition) = set_condition(sub_m) {
+                transport::Command::SetIf {
+                    key,
+                    value,
+                    condition,
+                }
+            } else {
+                transport::Command::Set { key, value }
+            };
+            let resp = send_command(&mut request_writer, response_reader, &cmd)?;
+            if let Some(e) = resp.error {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
 
             Ok(())
         }
         Some(("get", sub_m)) => {
-            let key = sub_m.get_one::<String>("key").unwrap();
-
-            let s = serialize(&Request::Get {
-                key: key.to_string(),
-            });
-            request_writer.write_all(s.as_bytes())?;
-            request_writer.flush()?;
-
-            let mut de = serde_json::Deserializer::from_reader(response_reader);
-            let resp = Response::deserialize(&mut de)?;
-            if let Some(_e) = resp.error {
-                println!("Key not found");
-            } else {
-                println!("{}", resp.result);
+            let cmd = transport::Command::Get {
+                key: arg_bytes(sub_m, "key"),
+            };
+            let resp = send_command(&mut request_writer, response_reader, &cmd)?;
+            match (resp.value, resp.error) {
+                // values are printed as they are, since they may not be UTF-8.
+                (Some(value), None) => {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(&value)?;
+                    stdout.write_all(b"\n")?;
+                }
+                (None, None) => println!("{}", transport::KEY_NOT_FOUND),
+                (_, Some(e)) if e == transport::KEY_NOT_FOUND => println!("{}", e),
+                (_, Some(e)) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
             }
 
             Ok(())
         }
         Some(("rm", sub_m)) => {
-            let key = sub_m.get_one::<String>("key").unwrap();
+            let cmd = transport::Command::Remove {
+                key: arg_bytes(sub_m, "key"),
+            };
+            let resp = send_command(&mut request_writer, response_reader, &cmd)?;
+            if let Some(e) = resp.error {
+                eprintln!("{}", e);
+                return Err(KvsError::KeyNotFound);
+            }
 
-            let s = serialize(&Request::Rm {
-                key: key.to_string(),
-            });
-            request_writer.write_all(s.as_bytes())?;
-            request_writer.flush()?;
+            Ok(())
+        }
+        Some(("ttl", sub_m)) => {
+            let cmd = transport::Command::Ttl {
+                key: arg_bytes(sub_m, "key"),
+            };
+            let resp = send_command(&mut request_writer, response_reader, &cmd)?;
+            match resp.error {
+                Some(e) => println!("{}", e),
+                None => match resp.result.parse::<u64>() {
+                    // rounds up, so that a key is never reported with 0 seconds
+                    // left before it expires.
+                    Ok(ttl_ms) => println!("{}", ttl_ms.div_ceil(1000)),
+                    Err(_) => println!("{}", resp.result),
+                },
+            }
 
-            let mut de = serde_json::Deserializer::from_reader(response_reader);
-            let resp = Response::deserialize(&mut de)?;
+            Ok(())
+        }
+        Some(("persist", sub_m)) => {
+            let cmd = transport::Command::Persist {
+                key: arg_bytes(sub_m, "key"),
+            };
+            let resp = send_command(&mut request_writer, response_reader, &cmd)?;
             if let Some(e) = resp.error {
                 eprintln!("{}", e);
                 return Err(KvsError::KeyNotFound);
@@ -129,9 +228,154 @@ fn main() -> Result<()> {
 
             Ok(())
         }
+        Some(("multi", _)) => {
+            run_transaction(&mut request_writer, response_reader)?;
+            Ok(())
+        }
+        Some(("batch", sub_m)) => {
+            let ops: Vec<&OsString> = sub_m.get_many::<OsString>("ops").unwrap().collect();
+            let batch = match parse_batch(&ops) {
+                Some(batch) => batch,
+                None => {
+                    eprintln!("invalid batch, expected `set <KEY> <VALUE>` or `rm <KEY>` writes");
+                    std::process::exit(1);
+                }
+            };
+
+            let cmd = transport::Command::Batch(batch);
+            let resp = send_command(&mut request_writer, response_reader, &cmd)?;
+            if let Some(e) = resp.error {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+
+            Ok(())
+        }
         _ => {
             eprintln!("unimplemented method, run `help`");
             std::process::exit(1);
         }
     }
 }
+
+// returns the bytes of an argument as they are given, so that keys and values
+// are not limited to UTF-8.
+fn arg_bytes(matches: &ArgMatches, id: &str) -> Vec<u8> {
+    matches
+        .get_one::<OsString>(id)
+        .unwrap()
+        .as_encoded_bytes()
+        .to_vec()
+}
+
+// returns the condition of a conditional set, if any of its flags is given.
+fn set_condition(matches: &ArgMatches) -> Option<Condition> {
+    if matches.get_flag("if-absent") {
+        Some(Condition::Absent)
+    } else if matches.get_flag("if-present") {
+        Some(Condition::Present)
+    } else {
+        matches
+            .get_one::<OsString>("if-equals")
+            .map(|expected| Condition::Equals(expected.as_encoded_bytes().to_vec()))
+    }
+}
+
+// sends a command as a frame, and reads its response.
+fn send_command<W: Write, R: Read>(
+    request_writer: &mut W,
+    mut response_reader: R,
+    cmd: &transport::Command,
+) -> Result<Response> {
+    transport::write_command(request_writer, cmd)?;
+    transport::read_response(&mut response_reader)
+}
+
+// runs the lines of stdin in a transaction on the connection, printing the
+// values of the keys it reads.
+fn run_transaction<W: Write, R: Read>(
+    request_writer: &mut W,
+    mut response_reader: R,
+) -> Result<()> {
+    let resp = send_command(
+        request_writer,
+        &mut response_reader,
+        &transport::Command::Multi,
+    )?;
+    if let Some(e) = resp.error {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let mut words = line.trim().splitn(3, ' ');
+        let cmd = match (words.next(), words.next(), words.next()) {
+            (Some("get"), Some(key), None) => transport::Command::Get { key: key.into() },
+            (Some("set"), Some(key), Some(value)) => transport::Command::Set {
+                key: key.into(),
+                value: value.into(),
+            },
+            (Some("rm"), Some(key), None) => transport::Command::Remove { key: key.into() },
+            (Some("exec"), None, None) => break,
+            (Some("discard"), None, None) => {
+                send_command(
+                    request_writer,
+                    &mut response_reader,
+                    &transport::Command::Discard,
+                )?;
+                return Ok(());
+            }
+            (Some(""), None, None) => continue,
+            _ => {
+                eprintln!("invalid command `{}`", line);
+                continue;
+            }
+        };
+
+        let is_get = matches!(cmd, transport::Command::Get { .. });
+        let resp = send_command(request_writer, &mut response_reader, &cmd)?;
+        match (resp.value, resp.error) {
+            (Some(value), None) => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
+            }
+            (_, Some(e)) if is_get => println!("{}", e),
+            (_, Some(e)) => eprintln!("{}", e),
+            _ => {}
+        }
+    }
+
+    let resp = send_command(
+        request_writer,
+        &mut response_reader,
+        &transport::Command::Exec,
+    )?;
+    if let Some(e) = resp.error {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// parses the writes of a batch given as `set <KEY> <VALUE>` and `rm <KEY>` sequences.
+fn parse_batch(ops: &[&OsString]) -> Option<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    let mut ops = ops.iter();
+    while let Some(op) = ops.next() {
+        match op.to_str() {
+            Some("set") => {
+                let key = ops.next()?;
+                let val = ops.next()?;
+                batch.put(key.as_encoded_bytes(), val.as_encoded_bytes());
+            }
+            Some("rm") => {
+                batch.delete(ops.next()?.as_encoded_bytes());
+            }
+            _ => return None,
+        }
+    }
+
+    Some(batch)
+}
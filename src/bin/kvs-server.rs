@@ -1,16 +1,23 @@
 use std::{
     env::{self, current_dir},
     fs,
+    path::{Path, PathBuf},
     process::exit,
+    time::Duration,
 };
 
-use clap::{arg, builder::PossibleValue, command, value_parser};
+use clap::{arg, builder::PossibleValue, command, value_parser, ArgMatches};
 use kvs::{
     server::KvServer,
     thread_pool::{SharedQueueThreadPool, ThreadPool},
-    Result,
+    CachedEngine, Durability, KvStore, KvStoreOptions, KvsEngine, KvsError, Result, SledKvsEngine,
 };
-use log::{self, info};
+use log::{self, error, info};
+use serde::Deserialize;
+
+// ENGINE_FILE is the marker file in the data directory which keeps the name of
+// the engine that created the data in it.
+const ENGINE_FILE: &str = "engine";
 
 fn main() -> Result<()> {
     if env::var("KVS_LOG").is_err() {
@@ -43,19 +50,253 @@ fn main() -> Result<()> {
             .global(true)
             .value_parser([PossibleValue::new("kvs"), PossibleValue::new("sled")]),
         )
+        .arg(
+            arg!(
+                --config <FILE> "JSON file with the options of the kvs engine; the flags override it"
+            )
+            .required(false)
+            .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(
+                --"compaction-threshold" <BYTES> "Dead bytes that trigger a compaction"
+            )
+            .required(false)
+            .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(
+                --"garbage-ratio" <RATIO> "Ratio of dead bytes that makes a log file worth compacting"
+            )
+            .required(false)
+            .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(
+                --"max-compaction-segments" <COUNT> "Log files merged by a compaction at most"
+            )
+            .required(false)
+            .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(
+                --"max-segment-size" <BYTES> "Size of the active log file after which it is sealed"
+            )
+            .required(false)
+            .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(
+                --durability <MODE> "When the writes are synced to the disk"
+            )
+            .required(false)
+            .value_parser([
+                PossibleValue::new("always"),
+                PossibleValue::new("interval"),
+                PossibleValue::new("never"),
+            ]),
+        )
+        .arg(
+            arg!(
+                --"sync-interval-ms" <MILLIS> "Period of the syncs with the interval durability"
+            )
+            .required(false)
+            .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(
+                --"reader-cache-size" <COUNT> "Log files kept open for reading"
+            )
+            .required(false)
+            .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(
+                --"blob-threshold" <BYTES> "Size of the values that are written into blob files"
+            )
+            .required(false)
+            .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(
+                --"read-only" "Serves the reads only, without modifying the data directory; kvs engine only"
+            )
+            .required(false),
+        )
+        .arg(
+            arg!(
+                --"cache-size" <BYTES> "Size of the cache of the recently read values; 0 disables it"
+            )
+            .required(false)
+            .value_parser(value_parser!(u64)),
+        )
         .get_matches();
 
     info!("KV Store, version: {}", env!("CARGO_PKG_VERSION"));
 
     let ip = matches.get_one::<String>("ip").unwrap();
+    let engine = matches.get_one::<String>("engine").unwrap();
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    info!("Storage engine: {}", engine);
     info!("Listening at {} ", ip.to_string());
 
+    let config = match load_config(&matches) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            exit(1);
+        }
+    };
+    let read_only = matches.get_flag("read-only") || config.read_only.unwrap_or(false);
+    // sled opens its data directory writable, so it cannot serve it read-only.
+    if read_only && engine != "kvs" {
+        error!("Read-only mode is not supported by '{}' engine", engine);
+        exit(1);
+    }
+    let cache_size = flag_or(&matches, "cache-size", config.cache_size).unwrap_or(0);
+    let options = match store_options(&matches, &config) {
+        Ok(options) => options.read_only(read_only),
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            exit(1);
+        }
+    };
+
+    let dir = current_dir()?;
+    if let Some(prev_engine) = current_engine(&dir)? {
+        if &prev_engine != engine {
+            error!(
+                "Wrong engine! the data directory was created by '{}' engine, not '{}'",
+                prev_engine, engine
+            );
+            exit(1);
+        }
+    }
+    if !read_only {
+        fs::write(dir.join(ENGINE_FILE), engine)?;
+    }
+
+    match engine.as_str() {
+        "kvs" => run(KvStore::open_with(&dir, options)?, ip, cache_size),
+        "sled" => run(SledKvsEngine::new(sled::open(&dir)?), ip, cache_size),
+        _ => unreachable!("engine name is validated by the argument parser"),
+    }
+}
+
+fn run<E: KvsEngine>(engine: E, ip: &str, cache_size: u64) -> Result<()> {
+    if cache_size > 0 {
+        info!("Caching up to {} bytes of values", cache_size);
+        return serve(CachedEngine::new(engine, cache_size), ip);
+    }
+    serve(engine, ip)
+}
+
+fn serve<E: KvsEngine>(engine: E, ip: &str) -> Result<()> {
     let pool = SharedQueueThreadPool::new(48).unwrap();
 
-    let s = KvServer::new();
-    s.start(ip.to_string(), pool)?;
+    let s = KvServer::new(engine);
+    s.start(ip.to_string(), pool)
+}
+
+// Config is the content of the file given by --config. Every field is optional,
+// and the flags take precedence over the file.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    compaction_threshold: Option<u64>,
+    garbage_ratio: Option<f64>,
+    max_compaction_segments: Option<usize>,
+    max_segment_size: Option<u64>,
+    durability: Option<String>,
+    sync_interval_ms: Option<u64>,
+    reader_cache_size: Option<usize>,
+    blob_threshold: Option<u64>,
+    read_only: Option<bool>,
+    cache_size: Option<u64>,
+}
+
+fn load_config(matches: &ArgMatches) -> Result<Config> {
+    match matches.get_one::<PathBuf>("config") {
+        Some(path) => Ok(serde_json::from_slice::<Config>(&fs::read(path)?)?),
+        None => Ok(Config::default()),
+    }
+}
+
+// store_options builds the options of the kvs engine from the config file and
+// the flags, except for the read-only mode, which is checked against the engine
+// first.
+fn store_options(matches: &ArgMatches, config: &Config) -> Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::new();
+    if let Some(bytes) = flag_or(matches, "compaction-threshold", config.compaction_threshold) {
+        options = options.compaction_threshold(bytes);
+    }
+    if let Some(ratio) = flag_or(matches, "garbage-ratio", config.garbage_ratio) {
+        options = options.garbage_ratio(ratio);
+    }
+    if let Some(count) = flag_or(
+        matches,
+        "max-compaction-segments",
+        config.max_compaction_segments,
+    ) {
+        options = options.max_compaction_segments(count);
+    }
+    if let Some(bytes) = flag_or(matches, "max-segment-size", config.max_segment_size) {
+        options = options.max_segment_size(bytes);
+    }
+    if let Some(count) = flag_or(matches, "reader-cache-size", config.reader_cache_size) {
+        options = options.reader_cache_size(count);
+    }
+    if let Some(bytes) = flag_or(matches, "blob-threshold", config.blob_threshold) {
+        options = options.blob_threshold(bytes);
+    }
+
+    let interval = flag_or(matches, "sync-interval-ms", config.sync_interval_ms).unwrap_or(1000);
+    match flag_or(matches, "durability", config.durability.clone()).as_deref() {
+        Some("always") => options = options.durability(Durability::Always),
+        Some("interval") => {
+            options = options.durability(Durability::Interval(Duration::from_millis(interval)))
+        }
+        Some("never") | None => {}
+        Some(mode) => {
+            return Err(KvsError::InvalidOption(format!(
+                "unknown durability '{}'",
+                mode
+            )))
+        }
+    }
+
+    Ok(options)
+}
 
-    Ok(())
+// flag_or returns the value of the flag if it is given, or the value of the
+// config file otherwise.
+fn flag_or<T: Clone + Send + Sync + 'static>(
+    matches: &ArgMatches,
+    id: &str,
+    config: Option<T>,
+) -> Option<T> {
+    matches.get_one::<T>(id).cloned().or(config)
+}
+
+// current_engine returns the engine recorded in the data directory, if any.
+// Without the marker file, e.g. for a directory written by a version without it,
+// the engine is inferred from the files it created.
+fn current_engine(dir: &Path) -> Result<Option<String>> {
+    let engine_file = dir.join(ENGINE_FILE);
+    if engine_file.exists() {
+        return Ok(Some(fs::read_to_string(engine_file)?.trim().to_string()));
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(|name| name.to_str());
+        if name == Some("MANIFEST") || path.extension() == Some("log".as_ref()) {
+            return Ok(Some("kvs".to_string()));
+        }
+        if name == Some("conf") || name == Some("db") {
+            return Ok(Some("sled".to_string()));
+        }
+    }
+    Ok(None)
 }
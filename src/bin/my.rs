@@ -1,9 +1,9 @@
-use std::env::{self};
+use std::env::{self, current_dir};
 
 use kvs::{
     server::KvServer,
     thread_pool::{SharedQueueThreadPool, ThreadPool},
-    Result,
+    KvStore, Result,
 };
 use log::{self, debug, info};
 
@@ -19,7 +19,7 @@ fn main() -> Result<()> {
 
     let pool = SharedQueueThreadPool::new(48).unwrap();
 
-    let s = KvServer::new();
+    let s = KvServer::new(KvStore::open(current_dir()?)?);
     s.start("127.0.0.1:4000".to_string(), pool)?;
 
     Ok(())
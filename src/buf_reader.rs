@@ -1,37 +0,0 @@
-use crate::Result;
-use std::io::{BufReader, Read, Seek, SeekFrom};
-
-// reference: ping-cap/talent-course
-pub struct BufReaderWithPos<R: Read + Seek> {
-    pub reader: BufReader<R>,
-    pub pos: u64,
-}
-
-// methods for BufReaderWithPos
-impl<R: Read + Seek> BufReaderWithPos<R> {
-    pub fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
-        Ok(BufReaderWithPos {
-            reader: BufReader::new(inner),
-            pos,
-        })
-    }
-}
-
-// implement Read trait for BufReaderWithPos
-impl<R: Read + Seek> Read for BufReaderWithPos<R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let len = self.reader.read(buf)?;
-        self.pos += len as u64;
-        Ok(len)
-    }
-}
-
-// implement Seek trait for BufReaderWithPos
-impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
-    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        self.pos = self.reader.seek(pos)?;
-
-        Ok(self.pos)
-    }
-}
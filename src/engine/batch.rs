@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+/// BatchOp is a single write of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+/// WriteBatch groups writes on multiple keys which are applied atomically by
+/// `KvsEngine::write_batch`; even after a crash, either all of them are visible
+/// or none of them.
+///
+/// Unlike `KvsEngine::remove`, deleting a key that does not exist is not an
+/// error in a batch.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Put {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    pub fn delete(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Delete { key: key.into() });
+        self
+    }
+
+    /// Returns the writes of the batch in the order they are added.
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
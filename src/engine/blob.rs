@@ -0,0 +1,267 @@
+use super::{kv::Durability, usage::SegmentUsage};
+use crate::{buf_writer::BufWriterWithPos, KvsError, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+// Blob files keep the large values out of the log files, so that the compaction
+// of the logs does not copy them over and over. A value of at least
+// `KvStoreOptions::blob_threshold` bytes is appended to the active `N.blob`, and
+// only its position is logged, as a BlobRef record.
+//
+// A blob file starts with BLOB_MAGIC followed by its format version as u32
+// (little endian), and the values are laid out back to back after it. The
+// checksum of each value is kept in its BlobRef record. The blob files are not
+// tracked by the MANIFEST: a blob file that no live key refers to is removed on
+// open.
+//
+// The blob files have their own usage, and the blob GC rewrites the live values
+// of the blob files with the most dead bytes into a new blob file. The keys are
+// pointed to the new positions by logging their BlobRef records again.
+
+pub const BLOB_MAGIC: [u8; 4] = *b"KVSB";
+pub const BLOB_VERSION: u32 = 1;
+pub const BLOB_HEADER_LEN: u64 = 8;
+
+/// BlobPos is the position of a value in a blob file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobPos {
+    pub blob_idx: u32,
+    pub offset: u64,
+    pub len: u64,
+    /// The crc32 of the value.
+    pub crc: u32,
+}
+
+pub fn blob_path(dir: &Path, blob_idx: u32) -> PathBuf {
+    dir.join(format!("{}.blob", blob_idx))
+}
+
+/// Returns the indices of the blob files in the directory in order.
+pub fn blob_files(dir: &Path) -> Result<Vec<u32>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some("blob".as_ref()) {
+            continue;
+        }
+        if let Some(Ok(idx)) = path.file_stem().and_then(OsStr::to_str).map(str::parse) {
+            files.push(idx);
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Checks the header of the blob file `blob_idx`.
+pub fn check_header(blob_idx: u32, buf: &[u8]) -> Result<()> {
+    if buf.len() < BLOB_HEADER_LEN as usize || buf[..4] != BLOB_MAGIC {
+        return Err(KvsError::BlobCorruption(blob_idx, 0));
+    }
+    match u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) {
+        BLOB_VERSION => Ok(()),
+        v => Err(KvsError::UnsupportedFormat(v)),
+    }
+}
+
+// creates a new blob file, and writes the header into it.
+pub fn new_blob_file(path: &Path) -> Result<BufWriterWithPos<File>> {
+    let mut writer =
+        BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(path)?)?;
+    writer.write_all(&BLOB_MAGIC)?;
+    writer.write_all(&BLOB_VERSION.to_le_bytes())?;
+    writer.flush()?;
+
+    Ok(writer)
+}
+
+/// BlobWriter appends the large values into the active blob file. The blob
+/// file is created with the first value written into it, so a store without
+/// large values has no blob files. It is only used under the log_writer lock.
+pub struct BlobWriter {
+    dir: PathBuf,
+    blob_idx: Arc<AtomicU64>,
+    writer: Option<BufWriterWithPos<File>>,
+}
+
+impl BlobWriter {
+    /// Creates a writer of the blob file `blob_idx`, which is shared with the
+    /// readers to tell the sealed blob files apart.
+    pub fn new(dir: PathBuf, blob_idx: Arc<AtomicU64>) -> BlobWriter {
+        BlobWriter {
+            dir,
+            blob_idx,
+            writer: None,
+        }
+    }
+
+    /// Appends the value into the active blob file. The value is readable once
+    /// `finish` is called.
+    pub fn append(&mut self, value: &[u8]) -> Result<BlobPos> {
+        let blob_idx = self.blob_idx.load(Ordering::SeqCst) as u32;
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => self
+                .writer
+                .insert(new_blob_file(&blob_path(&self.dir, blob_idx))?),
+        };
+
+        let offset = writer.pos;
+        writer.write_all(value)?;
+        Ok(BlobPos {
+            blob_idx,
+            offset,
+            len: value.len() as u64,
+            crc: crc32fast::hash(value),
+        })
+    }
+
+    /// Flushes the values appended so far, and syncs them unless the store is
+    /// opened with `Durability::Never`, since a BlobRef must not outlive its
+    /// value in a crash. The blob file is sealed once it reaches `max_size`.
+    pub fn finish(&mut self, durability: Durability, max_size: u64) -> Result<()> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        writer.flush()?;
+        if durability != Durability::Never {
+            writer.writer.get_ref().sync_data()?;
+        }
+
+        if writer.pos >= max_size {
+            let blob_idx = self.blob_idx.load(Ordering::SeqCst);
+            self.seal(blob_idx + 1)?;
+            info!(
+                "sealed blob file {}, writing into {}",
+                blob_idx,
+                blob_idx + 1
+            );
+        }
+        Ok(())
+    }
+
+    /// Seals the active blob file, and reserves the index after it for the
+    /// blob GC. The new values are appended into the blob file after that.
+    pub fn reserve(&mut self) -> Result<u32> {
+        let blob_idx = self.blob_idx.load(Ordering::SeqCst);
+        self.seal(blob_idx + 2)?;
+        Ok((blob_idx + 1) as u32)
+    }
+
+    // the values are appended into the blob file `blob_idx` from now on. The
+    // sealed blob file is synced regardless of the durability, as the values
+    // in it may be relocated by the blob GC.
+    fn seal(&mut self, blob_idx: u64) -> Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+            writer.writer.get_ref().sync_all()?;
+        }
+        self.blob_idx.store(blob_idx, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// BlobIndex keeps the blob of every live key whose value is in a blob file,
+/// and the usage of the blob files. It is updated along with the key_dir.
+///
+/// It costs the length of the key plus about 80 bytes for each key with a blob,
+/// which is small next to the value it refers to.
+#[derive(Debug, Default)]
+pub struct BlobIndex {
+    live: HashMap<Vec<u8>, BlobPos>,
+    usage: SegmentUsage,
+}
+
+impl BlobIndex {
+    /// Records a write of the key; `blob` is the blob of its new value if the
+    /// value is in a blob file. The blob of its previous value becomes dead.
+    pub fn write(&mut self, key: &[u8], blob: Option<BlobPos>) {
+        let old = match blob {
+            Some(blob) => {
+                self.usage.written(blob.blob_idx, blob.len);
+                self.live.insert(key.to_vec(), blob)
+            }
+            None if self.live.is_empty() => None,
+            None => self.live.remove(key),
+        };
+        if let Some(old) = old {
+            self.usage.dead(old.blob_idx, old.len);
+        }
+    }
+
+    /// Returns whether the value of the key is still the blob.
+    pub fn is_live(&self, key: &[u8], blob: &BlobPos) -> bool {
+        self.live.get(key) == Some(blob)
+    }
+
+    /// Returns the live blobs in the blob files, along with their keys.
+    pub fn live_in(&self, blob_files: &BTreeSet<u32>) -> Vec<(Vec<u8>, BlobPos)> {
+        self.live
+            .iter()
+            .filter(|(_, blob)| blob_files.contains(&blob.blob_idx))
+            .map(|(key, blob)| (key.clone(), *blob))
+            .collect()
+    }
+
+    /// Rebuilds the usage of the blob files from their sizes once the logs are
+    /// replayed, counting the bytes that no live key refers to as dead, e.g.
+    /// the values whose BlobRef is lost in a crash. It returns the blob files
+    /// without any live values.
+    pub fn load_usage(&mut self, dir: &Path, blob_files: &[u32]) -> Result<Vec<u32>> {
+        let mut live_bytes: HashMap<u32, u64> = HashMap::new();
+        for blob in self.live.values() {
+            *live_bytes.entry(blob.blob_idx).or_default() += blob.len;
+        }
+
+        self.usage = SegmentUsage::default();
+        let mut unused = Vec::new();
+        for &idx in blob_files {
+            let len = fs::metadata(blob_path(dir, idx))?
+                .len()
+                .saturating_sub(BLOB_HEADER_LEN);
+            let live = live_bytes.get(&idx).copied().unwrap_or(0);
+            if live == 0 {
+                unused.push(idx);
+                continue;
+            }
+            self.usage.written(idx, len);
+            self.usage.dead(idx, len.saturating_sub(live));
+        }
+        Ok(unused)
+    }
+
+    /// Returns the number of dead bytes that the blob GC would reclaim.
+    pub fn reclaimable(&self, min_ratio: f64) -> u64 {
+        self.usage.reclaimable(min_ratio)
+    }
+
+    /// Returns up to `max` blob files worth collecting.
+    pub fn pick(&self, min_ratio: f64, max: usize) -> BTreeSet<u32> {
+        self.usage.pick(min_ratio, max).into_iter().collect()
+    }
+
+    /// Records the bytes of a blob file that are dead as soon as they are
+    /// written, e.g. the values relocated by the blob GC whose keys are
+    /// written in the meantime.
+    pub fn dead(&mut self, blob_idx: u32, len: u64) {
+        self.usage.written(blob_idx, len);
+        self.usage.dead(blob_idx, len);
+    }
+
+    /// Forgets a blob file that is collected.
+    pub fn retire(&mut self, blob_idx: u32) {
+        self.usage.retire(blob_idx);
+    }
+}
@@ -0,0 +1,306 @@
+use super::{BatchOp, Condition, KvsEngine, ReadSet, WriteBatch};
+use crate::Result;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::RangeBounds,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+// ENTRY_OVERHEAD is the memory a cached value costs besides its key and value,
+// counted against the capacity of the cache.
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// CachedEngine keeps the values of the recently read keys in memory, in front
+/// of another engine. The cache is bounded by the bytes of the keys and values
+/// it holds, and the least recently used values are evicted first.
+///
+/// Every write through the CachedEngine invalidates the keys it touches, so the
+/// engine behind it must not be written other than through the CachedEngine
+/// and its clones. The keys with an expiry are cached until they expire.
+#[derive(Clone)]
+pub struct CachedEngine<E: KvsEngine> {
+    engine: E,
+    cache: Arc<Mutex<LruCache>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+/// CacheStats is the number of reads served from the cache of a
+/// `CachedEngine`, and the number of reads that went to the engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl<E: KvsEngine> CachedEngine<E> {
+    /// Wraps the engine with a cache of `capacity` bytes.
+    pub fn new(engine: E, capacity: u64) -> CachedEngine<E> {
+        CachedEngine {
+            engine,
+            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Returns the engine behind the cache.
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    /// Returns the hits and misses of the cache so far, shared by every clone.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    // runs a write on the engine, and invalidates the keys it touches once it
+    // is done, whether it succeeds or not.
+    fn write<'a, T>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+        f: impl FnOnce(&E) -> Result<T>,
+    ) -> Result<T> {
+        let result = f(&self.engine);
+        let mut cache = self.cache.lock().unwrap();
+        for key in keys {
+            cache.invalidate(key);
+        }
+        result
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for CachedEngine<E> {
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        self.write([key.as_slice()], |engine| engine.set(key.clone(), value))
+    }
+
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        let token = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(value) = cache.get(&key, Instant::now()) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(value));
+            }
+            cache.begin_load(&key)
+        };
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let value = self.engine.get_bytes(key.clone());
+        // the expiry is read after the value; a write in between invalidates
+        // the load, so they belong to the same version of the key.
+        let ttl = match &value {
+            Ok(Some(_)) => Some(self.engine.ttl(key.clone())),
+            _ => None,
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        match (&value, ttl) {
+            (Ok(Some(value)), Some(Ok(ttl))) => {
+                let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+                cache.finish_load(key, token, value.clone(), expires_at);
+            }
+            _ => cache.cancel_load(&key, token),
+        }
+        value
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        self.write([key.as_slice()], |engine| engine.remove(key.clone()))
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let key = key.into();
+        self.write([key.as_slice()], |engine| {
+            engine.set_with_ttl(key.clone(), value, ttl)
+        })
+    }
+
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        self.engine.ttl(key)
+    }
+
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        self.write([key.as_slice()], |engine| engine.persist(key.clone()))
+    }
+
+    fn set_if(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        condition: Condition,
+    ) -> Result<()> {
+        let key = key.into();
+        self.write([key.as_slice()], |engine| {
+            engine.set_if(key.clone(), value, condition)
+        })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let keys = batch_keys(&batch);
+        self.write(keys.iter().map(Vec::as_slice), |engine| {
+            engine.write_batch(batch)
+        })
+    }
+
+    fn commit_transaction(&self, reads: ReadSet, batch: WriteBatch) -> Result<()> {
+        let keys = batch_keys(&batch);
+        self.write(keys.iter().map(Vec::as_slice), |engine| {
+            engine.commit_transaction(reads, batch)
+        })
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.engine.scan_bytes(range, limit)
+    }
+}
+
+fn batch_keys(batch: &WriteBatch) -> Vec<Vec<u8>> {
+    batch
+        .ops()
+        .iter()
+        .map(|op| match op {
+            BatchOp::Put { key, .. } | BatchOp::Delete { key } => key.clone(),
+        })
+        .collect()
+}
+
+// LruCache maps the keys to their values, ordered by their last use.
+//
+// A value read from the engine is cached only if its key is not written while
+// it is read: the read is registered with a token by begin_load, and the write
+// drops the token, so a value that is stale by the time it is read is never
+// cached.
+struct LruCache {
+    capacity: u64,
+    size: u64,
+    entries: HashMap<Vec<u8>, Entry>,
+    // order maps the last use of each cached key to the key.
+    order: BTreeMap<u64, Vec<u8>>,
+    // loads keeps the token of the reads in flight by their keys.
+    loads: HashMap<Vec<u8>, u64>,
+    clock: u64,
+}
+
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+    last_used: u64,
+}
+
+impl Entry {
+    fn charge(&self, key: &[u8]) -> u64 {
+        (key.len() + self.value.len()) as u64 + ENTRY_OVERHEAD
+    }
+}
+
+impl LruCache {
+    fn new(capacity: u64) -> LruCache {
+        LruCache {
+            capacity,
+            size: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            loads: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn get(&mut self, key: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let entry = self.entries.get(key)?;
+        if entry.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            self.invalidate(key);
+            return None;
+        }
+
+        let last_used = entry.last_used;
+        let clock = self.tick();
+        let entry = self.entries.get_mut(key).unwrap();
+        entry.last_used = clock;
+        let key = self.order.remove(&last_used).unwrap();
+        self.order.insert(clock, key);
+        Some(entry.value.clone())
+    }
+
+    fn begin_load(&mut self, key: &[u8]) -> u64 {
+        let token = self.tick();
+        self.loads.insert(key.to_vec(), token);
+        token
+    }
+
+    // caches the value read by the load, unless the key is written since the
+    // load began.
+    fn finish_load(
+        &mut self,
+        key: Vec<u8>,
+        token: u64,
+        value: Vec<u8>,
+        expires_at: Option<Instant>,
+    ) {
+        if self.loads.get(&key) != Some(&token) {
+            return;
+        }
+        self.invalidate(&key);
+
+        let entry = Entry {
+            value,
+            expires_at,
+            last_used: self.tick(),
+        };
+        let charge = entry.charge(&key);
+        if charge > self.capacity {
+            return;
+        }
+        while self.size + charge > self.capacity {
+            let (_, lru) = self.order.pop_first().unwrap();
+            let evicted = self.entries.remove(&lru).unwrap();
+            self.size -= evicted.charge(&lru);
+        }
+
+        self.size += charge;
+        self.order.insert(entry.last_used, key.clone());
+        self.entries.insert(key, entry);
+    }
+
+    // drops the load of a value that is not cached, e.g. of a key that does
+    // not exist.
+    fn cancel_load(&mut self, key: &[u8], token: u64) {
+        if self.loads.get(key) == Some(&token) {
+            self.loads.remove(key);
+        }
+    }
+
+    fn invalidate(&mut self, key: &[u8]) {
+        self.loads.remove(key);
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.charge(key);
+            self.order.remove(&entry.last_used);
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+/// Condition is the state a key needs to be in for `KvsEngine::set_if` to
+/// write it. An expired key does not exist.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Condition {
+    /// The key does not exist.
+    Absent,
+    /// The key exists.
+    Present,
+    /// The current value of the key is the given value.
+    Equals(Vec<u8>),
+}
+
+impl Condition {
+    /// Returns whether the condition holds for the current value of a key.
+    pub fn holds(&self, current: Option<&[u8]>) -> bool {
+        match self {
+            Condition::Absent => current.is_none(),
+            Condition::Present => current.is_some(),
+            Condition::Equals(expected) => current == Some(&expected[..]),
+        }
+    }
+}
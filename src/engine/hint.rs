@@ -0,0 +1,219 @@
+use super::blob::BlobPos;
+use crate::Result;
+use log::info;
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+// Hint files follow the idea of Bitcask hint files. Each compacted `N.log` gets a
+// `N.hint` next to it which includes the key and the position of every entry in the
+// log, so that `KvStore::open` can rebuild the key_dir without reading the values.
+//
+// A hint file starts with the HINT_MAGIC followed by its format version as u32,
+// and the rest of it is a list of records framed as in the logs:
+//
+// | crc32 (u32) | payload length (u32) | payload |
+//
+// where all integers are little endian and the crc32 is computed over the
+// payload. The payload of the first record is the size of the log file when the
+// hint is created (u64). Since compacted logs are never appended again, a hint
+// whose log size does not match the actual size of the log is stale.
+//
+// The payload of every other record is a HintEntry laid out as
+//
+// | starting pos (u64) | length (u64) | flags (u8) | key length (u32) | key |
+//
+// followed by the expiry time (u64) if the entry has one, and by the blob of
+// the value, laid out as in a BlobRef record, if the entry has one.
+
+const HINT_MAGIC: [u8; 4] = *b"KVSH";
+const HINT_VERSION: u32 = 1;
+const HINT_HEADER_LEN: usize = 8;
+// the length of an entry without its key, expiry and blob.
+const ENTRY_HEADER_LEN: usize = 21;
+
+const FLAG_EXPIRY: u8 = 1;
+const FLAG_TOMBSTONE: u8 = 2;
+const FLAG_BLOB: u8 = 4;
+
+/// HintEntry is the position of a live entry in a compacted log file, or of a
+/// Remove record kept in the log by the compaction.
+#[derive(Debug)]
+pub struct HintEntry {
+    pub key: Vec<u8>,
+    pub starting_pos: u64,
+    pub len: u64,
+    pub expires_at: Option<u64>,
+    pub tombstone: bool,
+    /// The blob of the value, if the entry is a BlobRef record.
+    pub blob: Option<BlobPos>,
+}
+
+impl HintEntry {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(ENTRY_HEADER_LEN + self.key.len());
+        buf.extend_from_slice(&self.starting_pos.to_le_bytes());
+        buf.extend_from_slice(&self.len.to_le_bytes());
+        let mut flags = 0;
+        if self.expires_at.is_some() {
+            flags |= FLAG_EXPIRY;
+        }
+        if self.tombstone {
+            flags |= FLAG_TOMBSTONE;
+        }
+        if self.blob.is_some() {
+            flags |= FLAG_BLOB;
+        }
+        buf.push(flags);
+        buf.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.key);
+        if let Some(expires_at) = self.expires_at {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        if let Some(blob) = &self.blob {
+            buf.extend_from_slice(&blob.blob_idx.to_le_bytes());
+            buf.extend_from_slice(&blob.offset.to_le_bytes());
+            buf.extend_from_slice(&blob.len.to_le_bytes());
+            buf.extend_from_slice(&blob.crc.to_le_bytes());
+        }
+        buf
+    }
+
+    // decodes the payload of a record, or returns None if it is malformed.
+    fn decode(mut buf: &[u8]) -> Option<HintEntry> {
+        let starting_pos = take_u64(&mut buf)?;
+        let len = take_u64(&mut buf)?;
+        let flags = *take(&mut buf, 1)?.first()?;
+        let key_len = take_u32(&mut buf)? as usize;
+        let key = take(&mut buf, key_len)?.to_vec();
+        let expires_at = match flags & FLAG_EXPIRY {
+            0 => None,
+            _ => Some(take_u64(&mut buf)?),
+        };
+        let blob = match flags & FLAG_BLOB {
+            0 => None,
+            _ => Some(BlobPos {
+                blob_idx: take_u32(&mut buf)?,
+                offset: take_u64(&mut buf)?,
+                len: take_u64(&mut buf)?,
+                crc: take_u32(&mut buf)?,
+            }),
+        };
+        if !buf.is_empty() {
+            return None;
+        }
+
+        Some(HintEntry {
+            key,
+            starting_pos,
+            len,
+            expires_at,
+            tombstone: flags & FLAG_TOMBSTONE != 0,
+            blob,
+        })
+    }
+}
+
+pub fn hint_path(dir: &Path, log_idx: u32) -> PathBuf {
+    dir.join(format!("{}.hint", log_idx))
+}
+
+/// Writes the hint file of the log `log_idx`.
+///
+/// The hint is written into a temporary file first and renamed afterwards, so a
+/// crash never leaves a partially written hint behind.
+pub fn write_hint(dir: &Path, log_idx: u32, log_len: u64, entries: &[HintEntry]) -> Result<()> {
+    let path = hint_path(dir, log_idx);
+    let tmp_path = path.with_extension("hint.tmp");
+
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(&HINT_MAGIC)?;
+    writer.write_all(&HINT_VERSION.to_le_bytes())?;
+    write_record(&mut writer, &log_len.to_le_bytes())?;
+    for entry in entries {
+        write_record(&mut writer, &entry.encode())?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Loads the hint file of the log `log_idx`.
+///
+/// It returns `None` if there is no hint for the log, or the hint is stale or
+/// malformed; in that case the log needs to be replayed fully.
+pub fn load_hint(dir: &Path, log_idx: u32, log_len: u64) -> Result<Option<Vec<HintEntry>>> {
+    let path = hint_path(dir, log_idx);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content = fs::read(&path)?;
+    if content.len() < HINT_HEADER_LEN
+        || content[..4] != HINT_MAGIC
+        || content[4..HINT_HEADER_LEN] != HINT_VERSION.to_le_bytes()
+    {
+        info!("hint file {:?} has no known header, ignoring it", path);
+        return Ok(None);
+    }
+
+    let mut buf = &content[HINT_HEADER_LEN..];
+    match read_record(&mut buf) {
+        Some(header) if header == log_len.to_le_bytes() => {}
+        _ => {
+            info!("hint file {:?} is stale, ignoring it", path);
+            return Ok(None);
+        }
+    }
+
+    let mut entries = Vec::new();
+    while !buf.is_empty() {
+        match read_record(&mut buf).and_then(HintEntry::decode) {
+            Some(entry) => entries.push(entry),
+            None => {
+                info!("hint file {:?} is malformed, ignoring it", path);
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(Some(entries))
+}
+
+fn write_record<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    writer.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+// reads the payload of the record at the beginning of `buf`, or returns None if
+// the record is cut short or does not match its checksum.
+fn read_record<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let crc = take_u32(buf)?;
+    let len = take_u32(buf)? as usize;
+    let payload = take(buf, len)?;
+    (crc32fast::hash(payload) == crc).then_some(payload)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(head)
+}
+
+fn take_u32(buf: &mut &[u8]) -> Option<u32> {
+    take(buf, 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn take_u64(buf: &mut &[u8]) -> Option<u64> {
+    take(buf, 8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+}
@@ -0,0 +1,404 @@
+use super::kv::CommandPos;
+use crossbeam_skiplist::{map::Entry, SkipMap};
+
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::BTreeMap,
+    hint, iter,
+    ops::{Bound, Deref, RangeBounds},
+    sync::{
+        atomic::{self, AtomicU32, AtomicU64, AtomicUsize},
+        Arc, Mutex,
+    },
+};
+
+// INLINE_KEY_LEN is the length of the keys stored in the key_dir entry itself,
+// without a heap allocation of their own.
+const INLINE_KEY_LEN: usize = 22;
+
+/// KeyDir maps the keys to the position of their latest record in the logs,
+/// ordered by the keys.
+///
+/// Every key costs a single node of the skip list on 64-bit targets: 24 bytes
+/// for the key, 32 bytes for its position, 8 bytes of reference count and 16
+/// bytes of tower pointers on average, which is 80 bytes, plus the overhead of
+/// the allocator. The keys longer than 22 bytes are allocated on their own, and
+/// cost their length plus the allocator overhead on top of that. The keys with
+/// an expiry are kept in the `Expiries` of the store as well.
+///
+/// Every change of the key_dir takes the next sequence number. While a
+/// `Version` is pinned by a snapshot, the position each change supersedes is
+/// kept in the history under its sequence number, so the key_dir is read as of
+/// the version without a copy of it. The history is dropped once the versions
+/// needing it are released.
+#[derive(Default)]
+pub struct KeyDir {
+    map: SkipMap<Key, Slot>,
+    seq: AtomicU64,
+    // history maps a key and the sequence number of its change to the
+    // position before the change, or None if the key did not exist.
+    history: SkipMap<(Vec<u8>, u64), Option<CommandPos>>,
+    // changes maps the sequence numbers in the history to their keys, so the
+    // history is dropped in the order of the changes.
+    changes: SkipMap<u64, Vec<u8>>,
+    // versions counts the pinned versions by their sequence numbers; pinned is
+    // their total, read by the writers without the lock.
+    versions: Mutex<BTreeMap<u64, usize>>,
+    pinned: AtomicUsize,
+}
+
+impl KeyDir {
+    pub fn new() -> KeyDir {
+        KeyDir::default()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Entry<'_, Key, Slot>> {
+        self.map.get(key)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Returns the entries whose keys are in `range`, in key order.
+    pub fn range<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = Entry<'_, Key, Slot>> {
+        let range = (
+            range.start_bound().map(|key| Key::from(&key[..])),
+            range.end_bound().map(|key| Key::from(&key[..])),
+        );
+        self.map.range::<Key, (Bound<Key>, Bound<Key>)>(range)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Entry<'_, Key, Slot>> {
+        self.map.iter()
+    }
+
+    /// Points the key to `cmd_pos`, and returns the previous position of it.
+    /// The lookup and the insert are not atomic together, so the callers need
+    /// to be the only writer of the key_dir, e.g. by holding the log_writer
+    /// lock. The same goes for every change of the key_dir.
+    pub fn replace(&self, key: &[u8], cmd_pos: CommandPos) -> Option<CommandPos> {
+        match self.map.get(key) {
+            Some(entry) => {
+                let old_cmd = entry.value().load();
+                self.supersede(key, Some(old_cmd));
+                entry.value().store(cmd_pos);
+                Some(old_cmd)
+            }
+            None => {
+                self.supersede(key, None);
+                self.map.insert(Key::from(key), Slot::new(cmd_pos));
+                None
+            }
+        }
+    }
+
+    /// Removes the key, and returns its position if it exists.
+    pub fn remove(&self, key: &[u8]) -> Option<CommandPos> {
+        let entry = self.map.get(key)?;
+        let old_cmd = entry.value().load();
+        self.supersede(key, Some(old_cmd));
+        entry.remove();
+        Some(old_cmd)
+    }
+
+    /// Points the key to `cmd_pos` if it is still at `source`, e.g. when its
+    /// record is moved by the compaction, and returns whether it is moved.
+    pub fn relocate(&self, key: &[u8], source: &CommandPos, cmd_pos: CommandPos) -> bool {
+        match self.map.get(key) {
+            Some(entry) if entry.value().load().is_at(source) => {
+                self.supersede(key, Some(entry.value().load()));
+                entry.value().store(cmd_pos);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes the key if it is still at `source`, and returns whether it is
+    /// removed.
+    pub fn remove_at(&self, key: &[u8], source: &CommandPos) -> bool {
+        match self.map.get(key) {
+            Some(entry) if entry.value().load().is_at(source) => {
+                self.supersede(key, Some(entry.value().load()));
+                entry.remove();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Pins the current version of the key_dir. The writers of the key_dir need
+    /// to be blocked while it is pinned, as they are for a change.
+    pub fn pin(self: &Arc<Self>) -> Version {
+        let mut versions = self.versions.lock().unwrap();
+        let seq = self.seq.load(atomic::Ordering::SeqCst);
+        *versions.entry(seq).or_insert(0) += 1;
+        self.pinned.fetch_add(1, atomic::Ordering::SeqCst);
+        Version {
+            key_dir: Arc::clone(self),
+            seq,
+        }
+    }
+
+    // takes the sequence number of a change of the key, and keeps the position
+    // it supersedes while a version is pinned. It is kept before the change, so
+    // a reader seeing the change sees its history as well.
+    fn supersede(&self, key: &[u8], old_cmd: Option<CommandPos>) {
+        let seq = self.seq.fetch_add(1, atomic::Ordering::SeqCst) + 1;
+        if self.pinned.load(atomic::Ordering::SeqCst) > 0 {
+            self.history.insert((key.to_vec(), seq), old_cmd);
+            self.changes.insert(seq, key.to_vec());
+        }
+    }
+
+    // returns the position of the key as of the version `seq`, which is the
+    // one superseded by its first change after the version, if it has any.
+    fn get_at(&self, key: &[u8], seq: u64) -> Option<CommandPos> {
+        // the key_dir is read before the history, which is kept before the
+        // key_dir is changed.
+        let current = self.map.get(key).map(|entry| entry.value().load());
+        match self
+            .history
+            .lower_bound(Bound::Excluded(&(key.to_vec(), seq)))
+        {
+            Some(entry) if entry.key().0 == key => *entry.value(),
+            _ => current,
+        }
+    }
+
+    // returns the first key after `bound` either in the key_dir or in the
+    // history, which has every key of a pinned version removed since then.
+    fn next_key(&self, bound: Bound<&[u8]>) -> Option<Vec<u8>> {
+        let current = self
+            .map
+            .lower_bound(bound)
+            .map(|entry| entry.key().to_vec());
+        let history_bound = match bound {
+            Bound::Included(key) => Bound::Included((key.to_vec(), 0)),
+            Bound::Excluded(key) => Bound::Excluded((key.to_vec(), u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let removed = self
+            .history
+            .lower_bound(history_bound.as_ref())
+            .map(|entry| entry.key().0.clone());
+        match (current, removed) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    // releases a version, and drops the history no pinned version needs.
+    fn unpin(&self, seq: u64) {
+        let mut versions = self.versions.lock().unwrap();
+        if let Some(count) = versions.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                versions.remove(&seq);
+            }
+        }
+        self.pinned.fetch_sub(1, atomic::Ordering::SeqCst);
+
+        // a change at or before the oldest pinned version is never read.
+        let oldest = versions.keys().next().copied().unwrap_or(u64::MAX);
+        for change in self.changes.range(..=oldest) {
+            self.history
+                .remove(&(change.value().clone(), *change.key()));
+            change.remove();
+        }
+    }
+}
+
+/// Version is the key_dir as of the moment it is pinned by `KeyDir::pin`.
+pub struct Version {
+    key_dir: Arc<KeyDir>,
+    seq: u64,
+}
+
+impl Version {
+    pub fn get(&self, key: &[u8]) -> Option<CommandPos> {
+        self.key_dir.get_at(key, self.seq)
+    }
+
+    /// Returns the keys in `range` along with their positions, in key order.
+    pub fn range<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = (Vec<u8>, CommandPos)> + '_ {
+        let mut bound = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        iter::from_fn(move || loop {
+            let key = self.key_dir.next_key(bound.as_ref().map(|key| &key[..]))?;
+            let past_end = match &end {
+                Bound::Included(end) => key > *end,
+                Bound::Excluded(end) => key >= *end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                return None;
+            }
+            let cmd_pos = self.get(&key);
+            bound = Bound::Excluded(key.clone());
+            if let Some(cmd_pos) = cmd_pos {
+                return Some((key, cmd_pos));
+            }
+        })
+    }
+}
+
+impl Drop for Version {
+    fn drop(&mut self) {
+        self.key_dir.unpin(self.seq);
+    }
+}
+
+/// Key is a key of the key_dir. The short keys are stored inline, so the most
+/// keys need no allocation besides their entry.
+pub enum Key {
+    Inline(u8, [u8; INLINE_KEY_LEN]),
+    Heap(Box<[u8]>),
+}
+
+impl From<&[u8]> for Key {
+    fn from(key: &[u8]) -> Key {
+        if key.len() <= INLINE_KEY_LEN {
+            let mut bytes = [0; INLINE_KEY_LEN];
+            bytes[..key.len()].copy_from_slice(key);
+            Key::Inline(key.len() as u8, bytes)
+        } else {
+            Key::Heap(key.into())
+        }
+    }
+}
+
+impl Deref for Key {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Key::Inline(len, bytes) => &bytes[..*len as usize],
+            Key::Heap(bytes) => bytes,
+        }
+    }
+}
+
+impl Borrow<[u8]> for Key {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+// the keys are ordered by their bytes, the same as the slices they borrow as.
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        self[..].cmp(&other[..])
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for Key {}
+
+/// Slot keeps the position of a key in the key_dir. The position of a key that
+/// is written again is updated in place: `SkipMap::insert` removes the previous
+/// entry before adding the new one, and a reader could miss the key in between.
+///
+/// The position is packed into atomics guarded by a sequence lock, which costs
+/// 32 bytes against the 56 bytes of a `RwLock<CommandPos>`. A reader retries
+/// while the position is being stored, and never blocks the writer.
+pub struct Slot {
+    // seq is odd while the position is being stored.
+    seq: AtomicU32,
+    log_idx: AtomicU32,
+    starting_pos: AtomicU64,
+    len: AtomicU64,
+    // expires_at is 0 for the keys without an expiry; an expiry at 0 is stored
+    // as 1, which is in the past all the same.
+    expires_at: AtomicU64,
+}
+
+impl Slot {
+    fn new(cmd_pos: CommandPos) -> Slot {
+        Slot {
+            seq: AtomicU32::new(0),
+            log_idx: AtomicU32::new(cmd_pos.log_idx),
+            starting_pos: AtomicU64::new(cmd_pos.starting_pos),
+            len: AtomicU64::new(cmd_pos.len),
+            expires_at: AtomicU64::new(pack_expiry(cmd_pos.expires_at)),
+        }
+    }
+
+    pub fn load(&self) -> CommandPos {
+        loop {
+            let seq = self.seq.load(atomic::Ordering::Acquire);
+            if seq % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+
+            let cmd_pos = CommandPos {
+                log_idx: self.log_idx.load(atomic::Ordering::Relaxed),
+                starting_pos: self.starting_pos.load(atomic::Ordering::Relaxed),
+                len: self.len.load(atomic::Ordering::Relaxed),
+                expires_at: match self.expires_at.load(atomic::Ordering::Relaxed) {
+                    0 => None,
+                    expires_at => Some(expires_at),
+                },
+            };
+            atomic::fence(atomic::Ordering::Acquire);
+            if self.seq.load(atomic::Ordering::Relaxed) == seq {
+                return cmd_pos;
+            }
+        }
+    }
+
+    pub(super) fn store(&self, cmd_pos: CommandPos) {
+        let mut seq = self.seq.load(atomic::Ordering::Relaxed);
+        loop {
+            if seq % 2 == 1 {
+                hint::spin_loop();
+                seq = self.seq.load(atomic::Ordering::Relaxed);
+                continue;
+            }
+            match self.seq.compare_exchange_weak(
+                seq,
+                seq.wrapping_add(1),
+                atomic::Ordering::Acquire,
+                atomic::Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => seq = current,
+            }
+        }
+        atomic::fence(atomic::Ordering::Release);
+
+        self.log_idx
+            .store(cmd_pos.log_idx, atomic::Ordering::Relaxed);
+        self.starting_pos
+            .store(cmd_pos.starting_pos, atomic::Ordering::Relaxed);
+        self.len.store(cmd_pos.len, atomic::Ordering::Relaxed);
+        self.expires_at
+            .store(pack_expiry(cmd_pos.expires_at), atomic::Ordering::Relaxed);
+        self.seq
+            .store(seq.wrapping_add(2), atomic::Ordering::Release);
+    }
+}
+
+fn pack_expiry(expires_at: Option<u64>) -> u64 {
+    expires_at.map_or(0, |expires_at| expires_at.max(1))
+}
@@ -1,307 +1,1321 @@
-use crate::{
-    buf_reader::BufReaderWithPos, buf_writer::BufWriterWithPos, server::TxMessage, KvsEngine,
-    KvsError, Result,
+use super::{
+    batch::{BatchOp, WriteBatch},
+    blob::{self, BlobIndex, BlobPos, BlobWriter},
+    condition::Condition,
+    hint::{self, HintEntry},
+    keydir::{KeyDir, Slot},
+    manifest::Manifest,
+    options::KvStoreOptions,
+    reader::KvsReader,
+    record::{self, Frame, Record},
+    snapshot::{SegmentPins, Snapshot},
+    transaction::ReadSet,
+    usage::SegmentUsage,
+    write_queue::WriteQueue,
 };
-use crossbeam_channel::Sender;
-use dashmap::DashMap;
-use kvs_protocol::{
-    deserializer::deserialize as kvs_deserialize, parser::KvReqParser, request::Request,
-    serializer::serialize as kvs_serialize,
-};
-use log::info;
+use crate::{buf_writer::BufWriterWithPos, KvsEngine, KvsError, Result};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use crossbeam_skiplist::SkipSet;
+use kvs_protocol::parser::KvReqParser;
+use log::{error, info, warn};
+use rayon::prelude::*;
 
 use std::{
-    cell::RefCell,
-    collections::BTreeMap,
+    collections::BTreeSet,
     ffi::OsStr,
     fs::{self, File, OpenOptions},
-    io::{self, Read, Seek, SeekFrom, Write},
+    io::{self, Read, Write},
+    ops::RangeBounds,
     path::{Path, PathBuf},
     result,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc, Mutex, RwLock,
+        Arc, Mutex,
     },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
     u32,
 };
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// SWEEP_INTERVAL is how often the expired keys are removed from the logs.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// REPLAY_CHUNK_SIZE is how many bytes of a log file are read at once while it is
+// replayed.
+const REPLAY_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Durability decides when the writes of a KvStore are synced to the disk.
+///
+/// Every write is flushed to the OS before it is acknowledged; a write which
+/// is not synced yet can still be lost on a power loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Syncs the log file on every write.
+    Always,
+    /// Syncs the log file periodically from a background thread.
+    Interval(Duration),
+    /// Leaves syncing to the OS, unless `KvStore::sync` is called.
+    Never,
+}
+
+/// Expiries orders the keys with an expiry by their expiry time, so that the
+/// sweeper finds the expired keys without going through the whole key_dir. An
+/// entry may be stale, if the key is set again or removed afterwards.
+pub type Expiries = SkipSet<(u64, Vec<u8>)>;
 
 #[derive(Debug, Clone, Copy)]
 pub struct CommandPos {
     pub log_idx: u32,
     pub starting_pos: u64,
     pub len: u64,
+    /// The expiry time of the key as milliseconds since the unix epoch.
+    pub expires_at: Option<u64>,
 }
 
-pub struct KvsReader {
-    pub path: PathBuf,
-    // readers stores the reader of each log files as value, and the
-    // index of the logs as key.
-    // The primary reason for using RefCell here is to allow mutable access
-    // to the readers map even if the KvStoreReader itself is borrowed immutably
-    pub readers: RefCell<BTreeMap<u32, BufReaderWithPos<File>>>,
-    // In file systems and I/O operations, a "handle" typically refers to a reference or identifier for an open file or I/O resource.
-}
+impl CommandPos {
+    fn is_expired(&self, now: u64) -> bool {
+        is_expired(self.expires_at, now)
+    }
 
-impl Clone for KvsReader {
-    fn clone(&self) -> Self {
-        Self {
-            path: self.path.clone(),
-            readers: RefCell::new(BTreeMap::new()),
-        }
+    // returns whether both positions point to the same record.
+    pub(super) fn is_at(&self, other: &CommandPos) -> bool {
+        self.log_idx == other.log_idx && self.starting_pos == other.starting_pos
     }
 }
 
-impl KvsReader {
-    pub fn read_cmd_from_log_and_copy(
+// WriteOp is a write waiting in the write queue.
+enum WriteOp {
+    // logs the record; removing a key that does not exist fails.
+    Record(Record),
+    // drops the expiry of the key by logging its value again without it.
+    Persist(Vec<u8>),
+    // logs the Set record only if the key satisfies the condition.
+    SetIf(Record, Condition),
+    // logs the batch only if every key read by the transaction still has the
+    // value it was read with.
+    Transaction(ReadSet, Vec<Record>),
+}
+
+/// KvStore implements in memory database.
+#[derive(Clone)]
+pub struct KvStore {
+    // I can't read while someone is writing because compaction may happen
+    // which might invalidate current read by deleting a file for instance?
+    //
+    // PROBLEM: During compaction, i can't access the logs which prevents read access
+    // from functioning?
+    //
+    // log_writer is None if the store is opened read-only.
+    pub log_writer: Option<Arc<Mutex<BufWriterWithPos<File>>>>,
+    pub log_idx: Arc<AtomicU64>,
+    pub key_dir: Arc<KeyDir>,
+    expiries: Arc<Expiries>,
+    // usage is the live and dead bytes of each log file, updated along with
+    // the key_dir.
+    usage: Arc<Mutex<SegmentUsage>>,
+    // blobs is the blobs of the keys whose values are in blob files, and the
+    // usage of the blob files.
+    blobs: Arc<Mutex<BlobIndex>>,
+    // blob_writer is None if the store is opened read-only. It is locked only
+    // under the log_writer lock.
+    blob_writer: Option<Arc<Mutex<BlobWriter>>>,
+    pins: Arc<Mutex<SegmentPins>>,
+    // compactor is shared by all clones of the store; the background
+    // compaction thread is stopped once the last clone is dropped.
+    compactor: Option<Arc<BackgroundThread>>,
+    // syncer is the thread syncing the active log periodically, running only
+    // with `Durability::Interval`.
+    _syncer: Option<Arc<BackgroundThread>>,
+    // sweeper is the thread writing the tombstones of the expired keys.
+    _sweeper: Option<Arc<BackgroundThread>>,
+    options: KvStoreOptions,
+    manifest: Arc<Mutex<Manifest>>,
+    write_queue: Arc<WriteQueue<WriteOp>>,
+    reader: KvsReader,
+    // torn_write is the tail of the active log discarded on open.
+    torn_write: Option<TornWrite>,
+}
+
+/// TornWrite is the tail of the active log that is discarded when the store is
+/// opened, as it is left behind by a crash in the middle of a write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TornWrite {
+    /// Index of the log file holding the torn write.
+    pub log_idx: u32,
+    /// Offset of the torn write in the log file.
+    pub offset: u64,
+    /// Bytes discarded from the offset on.
+    pub bytes: u64,
+    /// Records in the discarded bytes, counting the torn one.
+    pub records: u64,
+}
+
+impl KvsEngine for KvStore {
+    fn set(&self, k: impl Into<Vec<u8>>, val: impl Into<Vec<u8>>) -> Result<()> {
+        self.write(WriteOp::Record(Record::Set {
+            key: k.into(),
+            value: val.into(),
+            expires_at: None,
+        }))
+    }
+
+    fn set_with_ttl(
         &self,
-        cmd_pos: &CommandPos,
-        writer: &mut BufWriterWithPos<File>,
-    ) -> Result<u64> {
-        let mut readers = self.readers.borrow_mut();
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.write(WriteOp::Record(Record::Set {
+            key: key.into(),
+            value: value.into(),
+            expires_at: Some(now_millis().saturating_add(ttl.as_millis() as u64)),
+        }))
+    }
 
-        if !readers.contains_key(&cmd_pos.log_idx) {
-            let curr_file_path = self.path.join(format!("{}.log", cmd_pos.log_idx));
-            let reader = BufReaderWithPos::new(File::open(&curr_file_path)?)?;
-            readers.insert(cmd_pos.log_idx, reader);
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        if let Some(entry) = self.key_dir.get(&key.into()) {
+            let cmd_pos = entry.value().load();
+            // expired keys stay in the key_dir until the sweeper removes them.
+            if cmd_pos.is_expired(now_millis()) {
+                return Ok(None);
+            }
+            match self.read_slot(entry.value(), cmd_pos)? {
+                Record::Set { value, .. } => Ok(Some(value)),
+                _ => Err(unexpected_record(entry.key())),
+            }
+        } else {
+            Ok(None)
         }
-        let reader = readers.get_mut(&cmd_pos.log_idx).unwrap();
+    }
 
-        reader.seek(SeekFrom::Start(cmd_pos.starting_pos))?;
-        let mut cmd_reader = reader.take(cmd_pos.len);
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        self.write(WriteOp::Record(Record::Remove { key: key.into() }))
+    }
 
-        let copied_bytes = io::copy(&mut cmd_reader, writer)?;
-        Ok(copied_bytes)
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        let now = now_millis();
+        match self.key_dir.get(&key.into()).map(|e| e.value().load()) {
+            Some(cmd_pos) if !cmd_pos.is_expired(now) => Ok(cmd_pos
+                .expires_at
+                .map(|expires_at| Duration::from_millis(expires_at - now))),
+            _ => Err(KvsError::KeyNotFound),
+        }
     }
 
-    pub fn read_cmd_from_log(&self, cmd_pos: &CommandPos) -> Result<Request> {
-        let mut readers = self.readers.borrow_mut();
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        self.write(WriteOp::Persist(key.into()))
+    }
+
+    fn set_if(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        condition: Condition,
+    ) -> Result<()> {
+        let cmd = Record::Set {
+            key: key.into(),
+            value: value.into(),
+            expires_at: None,
+        };
+        self.write(WriteOp::SetIf(cmd, condition))
+    }
 
-        if !readers.contains_key(&cmd_pos.log_idx) {
-            let curr_file_path = self.path.join(format!("{}.log", cmd_pos.log_idx));
-            let reader = BufReaderWithPos::new(File::open(&curr_file_path)?)?;
-            readers.insert(cmd_pos.log_idx, reader);
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
         }
-        let reader = readers.get_mut(&cmd_pos.log_idx).unwrap();
 
-        reader.seek(SeekFrom::Start(cmd_pos.starting_pos))?;
-        let mut cmd_reader = reader.take(cmd_pos.len);
+        self.write(WriteOp::Record(Record::Batch(batch_records(batch))))
+    }
 
-        let mut buf_str = String::new();
-        cmd_reader.read_to_string(&mut buf_str)?;
+    fn commit_transaction(&self, reads: ReadSet, batch: WriteBatch) -> Result<()> {
+        // nothing changes a read-only store, so the reads are always valid.
+        if batch.is_empty() && (reads.is_empty() || self.log_writer.is_none()) {
+            return Ok(());
+        }
+        self.write(WriteOp::Transaction(reads, batch_records(batch)))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        let live_entries = self
+            .key_dir
+            .range(range)
+            .map(|entry| (entry.value().load(), entry))
+            .filter(|(cmd_pos, _)| !cmd_pos.is_expired(now));
+        for (cmd_pos, entry) in live_entries.take(limit) {
+            match self.read_slot(entry.value(), cmd_pos)? {
+                Record::Set { key, value, .. } => pairs.push((key, value)),
+                _ => return Err(unexpected_record(entry.key())),
+            }
+        }
 
-        kvs_deserialize::<Request>(&mut buf_str)
-            .map_err(|e| KvsError::KvsDeserializer(buf_str, e.to_string()))
+        Ok(pairs)
     }
 }
 
 /// KvStore implements in memory database.
-#[derive(Clone)]
-pub struct KvStore {
-    // I can't read while someone is writing because compaction may happen
-    // which might invalidate current read by deleting a file for instance?
-    //
-    // PROBLEM: During compaction, i can't access the logs which prevents read access
-    // from functioning?
-    pub log_writer: Arc<Mutex<BufWriterWithPos<File>>>,
-    pub tx_compaction: Option<Sender<TxMessage>>,
-    pub log_idx: Arc<AtomicU64>,
-    pub key_dir: Arc<DashMap<String, CommandPos>>,
-    pub uncompacted: Arc<RwLock<u64>>,
-    reader: KvsReader,
-    path: PathBuf,
-}
+impl KvStore {
+    /// Opens the store with the default options.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::new())
+    }
 
-impl KvsEngine for KvStore {
-    fn set(&self, k: String, val: String) -> Result<()> {
-        let mut writer = self.log_writer.lock().unwrap();
-        let prev_pos = writer.pos;
+    /// Opens the store, syncing the writes to the disk according to `durability`.
+    pub fn open_with_durability(
+        path: impl Into<PathBuf>,
+        durability: Durability,
+    ) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::new().durability(durability))
+    }
 
-        let c = Request::Set {
-            key: k.clone(),
-            val: val.clone(),
+    /// Opens the store with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        options.validate()?;
+        let path: PathBuf = path.into();
+
+        // the stores created before the MANIFEST replay every log file in the
+        // directory.
+        let manifest = match Manifest::load(&path)? {
+            Some(manifest) => manifest,
+            None => Manifest::new(log_files(&path)),
         };
-        writer.write(kvs_serialize(&c).as_bytes())?;
-        writer.flush()?;
-
-        // Perform insert and capture old command
-        let old_cmd_len = if let Some(old_cmd) = self.key_dir.insert(
-            k,
-            CommandPos {
-                log_idx: self.log_idx.load(Ordering::SeqCst) as u32,
-                starting_pos: prev_pos,
-                len: writer.pos - prev_pos,
-            },
-        ) {
-            old_cmd.len
-        } else {
-            0
+        if !options.read_only {
+            manifest.remove_orphans(&path)?;
+        }
+        let log_files = manifest.segments().to_vec();
+
+        let key_dir = Arc::new(KeyDir::new());
+        let expiries = Arc::new(Expiries::new());
+
+        // the log files are read in parallel, a few at a time so that only the
+        // writes of those are held in memory, and applied in the order they are
+        // written.
+        let mut usage = SegmentUsage::default();
+        let mut blobs = BlobIndex::default();
+        let mut torn_write = None;
+        for chunk in log_files.chunks(rayon::current_num_threads().max(1)) {
+            let indexes = chunk
+                .par_iter()
+                .map(|&lf_idx| {
+                    // only the active log may end with a torn write.
+                    let torn_tail = if Some(&lf_idx) != log_files.last() {
+                        TornTail::Fail
+                    } else if options.read_only {
+                        TornTail::Ignore
+                    } else {
+                        TornTail::Truncate
+                    };
+                    SegmentIndex::load(&path, lf_idx, torn_tail)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for index in indexes {
+                torn_write = torn_write.or(index.torn_write);
+                index.apply(&key_dir, &expiries, &mut usage, &mut blobs);
+            }
+        }
+
+        // the blob files are accounted by their sizes once every key is pointed
+        // to its blob, and the ones without any live values are removed.
+        let blob_files = blob::blob_files(&path)?;
+        let unused_blobs = blobs.load_usage(&path, &blob_files)?;
+        if !options.read_only {
+            for idx in unused_blobs {
+                info!("removing blob file {} without live values", idx);
+                remove_if_exists(&blob::blob_path(&path, idx))?;
+            }
+        }
+        let blob_idx = Arc::new(AtomicU64::new(
+            blob_files.last().map_or(1, |idx| *idx as u64 + 1),
+        ));
+
+        let log_idx = Arc::new(AtomicU64::new(*log_files.last().unwrap_or(&0) as u64));
+        let active_log = (!options.read_only).then(|| Arc::clone(&log_idx));
+        let active_blob = (!options.read_only).then(|| Arc::clone(&blob_idx));
+        let reader = KvsReader::new(
+            path.clone(),
+            options.reader_cache_size,
+            active_log,
+            active_blob,
+        );
+        let mut store = KvStore {
+            log_writer: None,
+            log_idx,
+            key_dir,
+            expiries,
+            usage: Arc::new(Mutex::new(usage)),
+            blobs: Arc::new(Mutex::new(blobs)),
+            blob_writer: None,
+            pins: Arc::new(Mutex::new(SegmentPins::default())),
+            compactor: None,
+            _syncer: None,
+            _sweeper: None,
+            options,
+            manifest: Arc::new(Mutex::new(manifest)),
+            write_queue: Arc::new(WriteQueue::new()),
+            reader,
+            torn_write,
         };
-        drop(writer);
+        if options.read_only {
+            return Ok(store);
+        }
 
-        // Update uncompacted outside of key_dir lock
-        if old_cmd_len > 0 {
-            let mut uncompacted = self.uncompacted.write().unwrap();
-            *uncompacted += old_cmd_len;
+        // create a new log file.
+        let new_log_file_idx = log_files.last().unwrap_or(&0) + 1;
+        let new_log_writer = new_segment(&path.join(format!("{}.log", new_log_file_idx)))?;
+        store
+            .manifest
+            .lock()
+            .unwrap()
+            .add(&path, new_log_file_idx)?;
+        store
+            .log_idx
+            .store(new_log_file_idx as u64, Ordering::SeqCst);
+        let active_log_writer = Arc::new(Mutex::new(new_log_writer));
+        let blob_writer = Arc::new(Mutex::new(BlobWriter::new(path.clone(), blob_idx)));
+
+        let worker = CompactionWorker {
+            log_writer: Arc::clone(&active_log_writer),
+            log_idx: Arc::clone(&store.log_idx),
+            key_dir: Arc::clone(&store.key_dir),
+            expiries: Arc::clone(&store.expiries),
+            usage: Arc::clone(&store.usage),
+            blobs: Arc::clone(&store.blobs),
+            blob_writer: Arc::clone(&blob_writer),
+            pins: Arc::clone(&store.pins),
+            manifest: Arc::clone(&store.manifest),
+            reader: store.reader.clone(),
+            options,
+            path: path.clone(),
+        };
+        let compactor = BackgroundThread::spawn("kvs-compaction", move |rx| worker.run(rx))?;
+        store.compactor = Some(Arc::new(compactor));
+
+        if let Durability::Interval(interval) = options.durability {
+            let log_writer = Arc::clone(&active_log_writer);
+            let syncer = BackgroundThread::spawn("kvs-sync", move |rx| {
+                run_periodic_sync(&log_writer, interval, rx)
+            })?;
+            store._syncer = Some(Arc::new(syncer));
         }
 
-        if *self.uncompacted.read().unwrap() > COMPACTION_THRESHOLD {
-            if let Some(sender) = &self.tx_compaction {
-                sender
-                    .send(TxMessage {
-                        log_idx: Arc::clone(&self.log_idx),
-                        path: self.path.to_owned(),
-                    })
-                    .unwrap();
+        let sweeper = ExpirySweeper {
+            log_writer: Arc::clone(&active_log_writer),
+            log_idx: Arc::clone(&store.log_idx),
+            key_dir: Arc::clone(&store.key_dir),
+            expiries: Arc::clone(&store.expiries),
+            usage: Arc::clone(&store.usage),
+            blobs: Arc::clone(&store.blobs),
+            manifest: Arc::clone(&store.manifest),
+            options,
+            path,
+        };
+        let sweeper = BackgroundThread::spawn("kvs-expiry", move |rx| sweeper.run(rx))?;
+        store._sweeper = Some(Arc::new(sweeper));
+
+        store.log_writer = Some(active_log_writer);
+        store.blob_writer = Some(blob_writer);
+        Ok(store)
+    }
+
+    /// Returns a point-in-time view of the store. The writers are blocked only
+    /// while the current version of the key_dir and the live log files are
+    /// pinned for the snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        let _writer = self.log_writer.as_ref().map(|w| w.lock().unwrap());
+        let version = self.key_dir.pin();
+        let segments = self.manifest.lock().unwrap().segments().to_vec();
+
+        Snapshot::new(
+            version,
+            &segments,
+            now_millis(),
+            self.reader.clone(),
+            Arc::clone(&self.pins),
+        )
+    }
+
+    /// Flushes the active log file and syncs it to the disk, regardless of the
+    /// durability mode of the store.
+    pub fn sync(&self) -> Result<()> {
+        match &self.log_writer {
+            Some(log_writer) => sync_writer(log_writer),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the torn write discarded from the tail of the active log when the
+    /// store is opened, if there is one. A store opened read-only skips it
+    /// without truncating the log file.
+    pub fn torn_write(&self) -> Option<TornWrite> {
+        self.torn_write
+    }
+
+    // reads the record at `cmd_pos`, loaded from the slot. A compaction may
+    // remove the log file in between, after pointing the slot to the merged
+    // log; the read is retried at the new position of the key then.
+    fn read_slot(&self, slot: &Slot, mut cmd_pos: CommandPos) -> Result<Record> {
+        loop {
+            match self.reader.read_record(&cmd_pos) {
+                Err(e) => {
+                    let current = slot.load();
+                    if current.is_at(&cmd_pos) {
+                        return Err(e);
+                    }
+                    cmd_pos = current;
+                }
+                record => return record,
             }
         }
+    }
 
-        Ok(())
+    // writes through the write queue, so that the concurrent writes are
+    // committed to the log together.
+    fn write(&self, op: WriteOp) -> Result<()> {
+        let log_writer = self.log_writer.as_ref().ok_or(KvsError::ReadOnly)?;
+        self.write_queue
+            .write(op, |batch| self.commit_batch(log_writer, batch))
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.key_dir.get(&key) {
-            match self.reader.read_cmd_from_log(cmd_pos.value())? {
-                Request::Set { val, .. } => Ok(Some(val)),
-                _ => Err(KvsError::UnexpectedCommandType(cmd_pos.key().to_owned())),
+    // commits a batch of writes with a single write into the active log, and a
+    // single sync if the store is opened with `Durability::Always`. The key_dir
+    // is updated only after the batch is written, so readers never see a position
+    // that is not in the log yet.
+    fn commit_batch(
+        &self,
+        log_writer: &Mutex<BufWriterWithPos<File>>,
+        batch: Vec<WriteOp>,
+    ) -> Vec<Result<()>> {
+        let mut results: Vec<Result<()>> = Vec::with_capacity(batch.len());
+        // a commit that panicked while writing may have left a partial record in
+        // the buffers of the writers, so the later batches are failed rather than
+        // appended after it.
+        let blob_writer = self.blob_writer.as_ref().unwrap();
+        let (mut writer, mut blob_writer) = match (log_writer.lock(), blob_writer.lock()) {
+            (Ok(writer), Ok(blob_writer)) => (writer, blob_writer),
+            _ => {
+                let msg = "the writers are poisoned by a panicked commit";
+                return batch
+                    .iter()
+                    .map(|_| Err(KvsError::IO(msg.to_string())))
+                    .collect();
             }
-        } else {
-            Ok(None)
+        };
+        let log_idx = self.log_idx.load(Ordering::SeqCst) as u32;
+        let now = now_millis();
+
+        let mut buf = Vec::new();
+        let mut written = Vec::with_capacity(batch.len());
+        // blob_refs keeps the records logged in place of the written ones whose
+        // large values are moved into the blob file.
+        let mut blob_refs = Vec::with_capacity(batch.len());
+        for (i, op) in batch.into_iter().enumerate() {
+            results.push(Ok(()));
+            let cmd = match self.to_record(op, &written, now) {
+                Ok(Some(cmd)) => cmd,
+                Ok(None) => continue,
+                Err(e) => {
+                    results[i] = Err(e);
+                    continue;
+                }
+            };
+            let blob_ref = match separate(&mut blob_writer, &cmd, self.options.blob_threshold) {
+                Ok(blob_ref) => blob_ref,
+                Err(e) => {
+                    results[i] = Err(e);
+                    continue;
+                }
+            };
+
+            let encoded = record::encode_record(blob_ref.as_ref().unwrap_or(&cmd));
+            let cmd_pos = CommandPos {
+                log_idx,
+                starting_pos: writer.pos + buf.len() as u64,
+                len: encoded.len() as u64,
+                expires_at: None,
+            };
+            buf.extend_from_slice(&encoded);
+            written.push((i, cmd, cmd_pos));
+            blob_refs.push(blob_ref);
         }
+
+        // the values in the blob file are written before the records referring
+        // to them.
+        let durability = self.options.durability;
+        let result = blob_writer
+            .finish(durability, self.options.max_segment_size)
+            .and_then(|_| write_and_sync(&mut writer, &buf, durability));
+        drop(blob_writer);
+        if let Err(e) = result {
+            let msg = e.to_string();
+            for (i, _, _) in written {
+                results[i] = Err(KvsError::IO(msg.clone()));
+            }
+            return results;
+        }
+
+        let mut usage = self.usage.lock().unwrap();
+        let mut blobs = self.blobs.lock().unwrap();
+        for ((_, cmd, cmd_pos), blob_ref) in written.into_iter().zip(blob_refs) {
+            let cmd = blob_ref.unwrap_or(cmd);
+            apply_cmd(
+                &self.key_dir,
+                &self.expiries,
+                &mut usage,
+                &mut blobs,
+                cmd,
+                cmd_pos,
+            );
+        }
+        let reclaimable = usage.reclaimable(self.options.garbage_ratio)
+            > self.options.compaction_threshold
+            || blobs.reclaimable(self.options.garbage_ratio) > self.options.compaction_threshold;
+        drop(blobs);
+        drop(usage);
+
+        seal_if_full(
+            &mut writer,
+            &self.reader.path,
+            &self.manifest,
+            &self.log_idx,
+            &self.options,
+        );
+        drop(writer);
+
+        if reclaimable {
+            if let Some(compactor) = &self.compactor {
+                compactor.notify();
+            }
+        }
+
+        results
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        // Use DashMap's remove method which returns the removed value
-        if let Some((_, old_cmd)) = self.key_dir.remove(&key) {
-            let mut buf_writer = self.log_writer.lock().unwrap();
-            let c = Request::Rm { key };
-            let pos_before_writing = buf_writer.pos;
-            buf_writer.writer.write(kvs_serialize(&c).as_bytes())?;
-            buf_writer.writer.flush()?;
-            let pos_after_writing = buf_writer.pos;
-            drop(buf_writer);
-
-            {
-                let mut uncompacted = self.uncompacted.write().unwrap();
-                *uncompacted += pos_after_writing - pos_before_writing;
-                *uncompacted += old_cmd.len;
-            }
-            if *self.uncompacted.read().unwrap() > COMPACTION_THRESHOLD {
-                if let Some(tx) = &self.tx_compaction {
-                    tx.send(TxMessage {
-                        log_idx: Arc::clone(&self.log_idx),
-                        path: self.path.to_owned(),
-                    })
-                    .unwrap();
+    // turns a queued write into the record to log, against the state of the key
+    // after the records written so far by the batch. It returns None if there is
+    // nothing to log.
+    //
+    // It runs under the log_writer lock, so the state of the key cannot change
+    // until the record is written.
+    fn to_record(
+        &self,
+        op: WriteOp,
+        written: &[(usize, Record, CommandPos)],
+        now: u64,
+    ) -> Result<Option<Record>> {
+        match op {
+            WriteOp::Record(Record::Remove { key }) => {
+                if !self.exists(written, &key, now) {
+                    return Err(KvsError::KeyNotFound);
+                }
+                Ok(Some(Record::Remove { key }))
+            }
+            WriteOp::Record(cmd) => Ok(Some(cmd)),
+            WriteOp::Persist(key) => match self.current(written, &key, now)? {
+                Some(Record::Set {
+                    value,
+                    expires_at: Some(_),
+                    ..
+                }) => Ok(Some(Record::Set {
+                    key,
+                    value,
+                    expires_at: None,
+                })),
+                Some(_) => Ok(None),
+                None => Err(KvsError::KeyNotFound),
+            },
+            WriteOp::SetIf(cmd, condition) => {
+                let holds = match &condition {
+                    Condition::Absent => !self.exists(written, cmd.key(), now),
+                    Condition::Present => self.exists(written, cmd.key(), now),
+                    Condition::Equals(_) => match self.current(written, cmd.key(), now)? {
+                        Some(Record::Set { value, .. }) => condition.holds(Some(&value)),
+                        _ => false,
+                    },
+                };
+                if !holds {
+                    return Err(KvsError::ConditionFailed);
                 }
+                Ok(Some(cmd))
             }
+            WriteOp::Transaction(reads, records) => {
+                for (key, read) in reads {
+                    let value = match self.current(written, &key, now)? {
+                        Some(Record::Set { value, .. }) => Some(value),
+                        _ => None,
+                    };
+                    if value != read {
+                        return Err(KvsError::TransactionConflict);
+                    }
+                }
+                // a transaction which only reads is validated without logging
+                // anything.
+                if records.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(Record::Batch(records)))
+            }
+        }
+    }
 
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+    // returns whether the key exists after the records written so far by the
+    // batch.
+    fn exists(&self, written: &[(usize, Record, CommandPos)], key: &[u8], now: u64) -> bool {
+        match pending_write(written, key) {
+            Some(record) => record.is_some_and(|r| !record_expired(r, now)),
+            None => self
+                .key_dir
+                .get(key)
+                .is_some_and(|e| !e.value().load().is_expired(now)),
         }
     }
+
+    // returns the Set record of the key after the records written so far by the
+    // batch, or None if the key does not exist.
+    fn current(
+        &self,
+        written: &[(usize, Record, CommandPos)],
+        key: &[u8],
+        now: u64,
+    ) -> Result<Option<Record>> {
+        let record = match pending_write(written, key) {
+            Some(record) => record.cloned(),
+            None => match self.key_dir.get(key).map(|e| e.value().load()) {
+                Some(cmd_pos) if !cmd_pos.is_expired(now) => {
+                    Some(self.reader.read_record(&cmd_pos)?)
+                }
+                _ => None,
+            },
+        };
+        Ok(record.filter(|r| !record_expired(r, now)))
+    }
 }
 
-/// KvStore implements in memory database.
-impl KvStore {
-    pub fn new(tx_compaction: Sender<TxMessage>, path: impl Into<PathBuf>) -> Result<KvStore> {
-        let mut store = KvStore::open(path)?;
-        if store.tx_compaction.is_none() {
-            store.tx_compaction.replace(tx_compaction);
+// returns the latest write of the key among the records written so far by a
+// batch; Some(None) if the key is removed, and None if the batch does not
+// touch the key.
+fn pending_write<'a>(
+    written: &'a [(usize, Record, CommandPos)],
+    key: &[u8],
+) -> Option<Option<&'a Record>> {
+    written
+        .iter()
+        .rev()
+        .flat_map(|(_, cmd, _)| match cmd {
+            Record::Batch(records) => records.iter().rev(),
+            _ => std::slice::from_ref(cmd).iter().rev(),
+        })
+        .find(|r| r.key() == key)
+        .map(|r| match r {
+            Record::Set { .. } => Some(r),
+            _ => None,
+        })
+}
+
+// writes the values of at least `blob_threshold` bytes of the record into the
+// blob file, and returns the record to log in its place, or None if the record
+// is logged as it is.
+fn separate(
+    blob_writer: &mut BlobWriter,
+    cmd: &Record,
+    blob_threshold: Option<u64>,
+) -> Result<Option<Record>> {
+    let is_large = |record: &Record| match (record, blob_threshold) {
+        (Record::Set { value, .. }, Some(threshold)) => value.len() as u64 >= threshold,
+        _ => false,
+    };
+
+    match cmd {
+        Record::Set {
+            key,
+            value,
+            expires_at,
+        } if is_large(cmd) => Ok(Some(Record::BlobRef {
+            key: key.clone(),
+            blob: blob_writer.append(value)?,
+            expires_at: *expires_at,
+        })),
+        Record::Batch(records) if records.iter().any(is_large) => {
+            let records = records
+                .iter()
+                .map(|record| {
+                    let blob_ref = separate(blob_writer, record, blob_threshold)?;
+                    Ok(blob_ref.unwrap_or_else(|| record.clone()))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Some(Record::Batch(records)))
         }
+        _ => Ok(None),
+    }
+}
 
-        Ok(store)
+// converts the writes of a batch into the records of a batch record.
+fn batch_records(batch: WriteBatch) -> Vec<Record> {
+    batch
+        .into_ops()
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Put { key, value } => Record::Set {
+                key,
+                value,
+                expires_at: None,
+            },
+            BatchOp::Delete { key } => Record::Remove { key },
+        })
+        .collect()
+}
+
+fn write_and_sync(
+    writer: &mut BufWriterWithPos<File>,
+    buf: &[u8],
+    durability: Durability,
+) -> Result<()> {
+    writer.write_all(buf)?;
+    writer.flush()?;
+    if durability == Durability::Always {
+        writer.writer.get_ref().sync_data()?;
     }
 
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path: PathBuf = path.into();
+    Ok(())
+}
+
+// BackgroundThread owns a thread working on behalf of a KvStore, such as the
+// compaction. The thread receives its signals through the channel; dropping the
+// BackgroundThread closes the channel, which makes the thread exit, and then
+// waits for it to finish.
+struct BackgroundThread {
+    name: &'static str,
+    tx: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundThread {
+    fn spawn<F>(name: &'static str, f: F) -> Result<BackgroundThread>
+    where
+        F: FnOnce(Receiver<()>) + Send + 'static,
+    {
+        let (tx, rx) = unbounded::<()>();
 
-        // get all log files in the given path
-        let log_files = log_files(&path);
-
-        let key_dir = Arc::new(DashMap::new());
-
-        let mut temp_readers = BTreeMap::new();
-        let mut uncompacted = 0 as u64;
-        for lf_idx in &log_files {
-            let curr_log_path = path.join(format!("{}.log", lf_idx));
-            let mut reader = BufReaderWithPos::new(File::open(curr_log_path)?)?;
-
-            let mut starting_pos = reader.seek(SeekFrom::Start(0))?;
-            let mut buffer = Vec::new();
-            reader.read_to_end(&mut buffer)?;
-            let mut parser = KvReqParser::new(&buffer);
-
-            while let Some(v) = parser.next() {
-                let parsed_str = String::from_utf8_lossy(v);
-                let _cmd: result::Result<Request, kvs_protocol::error::Error> =
-                    kvs_deserialize::<Request>(&parsed_str);
-
-                if let Ok(cmd) = _cmd {
-                    let read_so_far = parser.read_so_far() as u64;
-                    match cmd {
-                        Request::Set { key, val: _ } => {
-                            if let Some(old_cmd) = key_dir.insert(
-                                key,
-                                CommandPos {
-                                    log_idx: *lf_idx,
-                                    starting_pos,
-                                    len: read_so_far - starting_pos,
-                                },
-                            ) {
-                                uncompacted += old_cmd.len;
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || f(rx))?;
+
+        Ok(BackgroundThread {
+            name,
+            tx: Some(tx),
+            handle: Some(handle),
+        })
+    }
+
+    fn notify(&self) {
+        if let Some(tx) = &self.tx {
+            // the receiver only goes away while the store is being dropped.
+            let _ = tx.send(());
+        }
+    }
+}
+
+impl Drop for BackgroundThread {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("[{}]: background thread panicked", self.name);
+            }
+        }
+    }
+}
+
+// ExpirySweeper writes the tombstones of the expired keys into the log, so that
+// they do not come back as soon as the expiry is dropped, e.g. by a restart
+// with a clock set back. Like the CompactionWorker, it must not hold a KvStore.
+struct ExpirySweeper {
+    log_writer: Arc<Mutex<BufWriterWithPos<File>>>,
+    log_idx: Arc<AtomicU64>,
+    key_dir: Arc<KeyDir>,
+    expiries: Arc<Expiries>,
+    usage: Arc<Mutex<SegmentUsage>>,
+    blobs: Arc<Mutex<BlobIndex>>,
+    manifest: Arc<Mutex<Manifest>>,
+    options: KvStoreOptions,
+    path: PathBuf,
+}
+
+impl ExpirySweeper {
+    fn run(self, rx: Receiver<()>) {
+        loop {
+            if let Err(RecvTimeoutError::Disconnected) = rx.recv_timeout(SWEEP_INTERVAL) {
+                break;
+            }
+            if let Err(e) = self.sweep() {
+                error!("[expiry]: failed to remove the expired keys, err: {}", e);
+            }
+        }
+    }
+
+    fn sweep(&self) -> Result<()> {
+        let now = now_millis();
+        if self
+            .expiries
+            .front()
+            .is_none_or(|entry| entry.value().0 > now)
+        {
+            return Ok(());
+        }
+
+        // the writers are blocked while the tombstones are written, so the
+        // expired keys cannot be set again in the meantime.
+        let mut writer = self.log_writer.lock().unwrap();
+        let log_idx = self.log_idx.load(Ordering::SeqCst) as u32;
+
+        let mut buf = Vec::new();
+        let mut written = Vec::new();
+        while let Some(entry) = self.expiries.front() {
+            let (expires_at, key) = entry.value();
+            if *expires_at > now {
+                break;
+            }
+
+            let expired = self
+                .key_dir
+                .get(key)
+                .is_some_and(|e| e.value().load().expires_at == Some(*expires_at));
+            if expired {
+                let cmd = Record::Remove { key: key.clone() };
+                let encoded = record::encode_record(&cmd);
+                let cmd_pos = CommandPos {
+                    log_idx,
+                    starting_pos: writer.pos + buf.len() as u64,
+                    len: encoded.len() as u64,
+                    expires_at: None,
+                };
+                buf.extend_from_slice(&encoded);
+                written.push((cmd, cmd_pos));
+            }
+            entry.remove();
+        }
+
+        if written.is_empty() {
+            return Ok(());
+        }
+        write_and_sync(&mut writer, &buf, self.options.durability)?;
+        info!("[expiry]: removed {} expired keys", written.len());
+
+        // the compaction is triggered by the next write, if needed.
+        let mut usage = self.usage.lock().unwrap();
+        let mut blobs = self.blobs.lock().unwrap();
+        for (cmd, cmd_pos) in written {
+            apply_cmd(
+                &self.key_dir,
+                &self.expiries,
+                &mut usage,
+                &mut blobs,
+                cmd,
+                cmd_pos,
+            );
+        }
+        drop(blobs);
+        drop(usage);
+        seal_if_full(
+            &mut writer,
+            &self.path,
+            &self.manifest,
+            &self.log_idx,
+            &self.options,
+        );
+
+        Ok(())
+    }
+}
+
+// CompactionWorker holds the state of the KvStore that the compaction thread
+// needs. It must not hold a KvStore itself, otherwise the store would never be
+// dropped and the thread would never stop.
+struct CompactionWorker {
+    log_writer: Arc<Mutex<BufWriterWithPos<File>>>,
+    log_idx: Arc<AtomicU64>,
+    key_dir: Arc<KeyDir>,
+    expiries: Arc<Expiries>,
+    usage: Arc<Mutex<SegmentUsage>>,
+    blobs: Arc<Mutex<BlobIndex>>,
+    blob_writer: Arc<Mutex<BlobWriter>>,
+    pins: Arc<Mutex<SegmentPins>>,
+    manifest: Arc<Mutex<Manifest>>,
+    reader: KvsReader,
+    options: KvStoreOptions,
+    path: PathBuf,
+}
+
+impl CompactionWorker {
+    fn run(self, rx: Receiver<()>) {
+        while rx.recv().is_ok() {
+            // writers keep signalling until the compaction reclaims the dead
+            // bytes, so drop the signals queued up in the meantime.
+            while rx.try_recv().is_ok() {}
+
+            let ratio = self.options.garbage_ratio;
+            let reclaimable = self.usage.lock().unwrap().reclaimable(ratio);
+            if reclaimable > self.options.compaction_threshold {
+                if let Err(e) = self.compact() {
+                    error!("[compaction]: failed to compact logs, err: {}", e);
+                }
+            }
+
+            let reclaimable = self.blobs.lock().unwrap().reclaimable(ratio);
+            if reclaimable > self.options.compaction_threshold {
+                if let Err(e) = self.collect_blobs() {
+                    error!("[blob gc]: failed to collect blob files, err: {}", e);
+                }
+            }
+        }
+        info!("[compaction]: store is dropped, stopping the compaction thread");
+    }
+
+    // compaction runs merging of bitcask, on the log files with the highest
+    // ratio of dead bytes only. The writers are blocked only while the logs are
+    // picked and while the key_dir is pointed to the merged log; the logs are
+    // merged in between, while the writers append into a new active log.
+    //
+    // 1- the active log file is sealed, since it is usually the one with the most
+    // dead bytes, and the new writes are moved to log_idx + 2. The picked log
+    // files are merged into log_idx + 1, which is placed after all of them and
+    // before the writes made during the compaction; it includes the records of the
+    // picked logs that are still active in the key_dir.
+    // 2- after creating this new log file, the key_dir is pointed to it for the
+    // keys that are not written again in the meantime, and the picked log files
+    // are removed.
+    //
+    // A Remove record is dropped only if every log file older than it is merged
+    // as well; otherwise it is kept, since an older log may still hold a record
+    // of the removed key, which would come back once the logs are replayed.
+    fn compact(&self) -> Result<()> {
+        let (picked, keep_tombstones, new_compaction_log_idx) = {
+            let mut log_writer = self.log_writer.lock().unwrap();
+            let usage = self.usage.lock().unwrap();
+
+            let picked: BTreeSet<u32> = usage
+                .pick(
+                    self.options.garbage_ratio,
+                    self.options.max_compaction_segments,
+                )
+                .into_iter()
+                .collect();
+            if picked.is_empty() {
+                return Ok(());
+            }
+            let keep_tombstones: BTreeSet<u32> = picked
+                .iter()
+                .copied()
+                .filter(|idx| usage.has_older(*idx, &picked))
+                .collect();
+
+            let log_idx = self.log_idx.load(Ordering::SeqCst) + 2;
+            rotate(&mut log_writer, &self.path, &self.manifest, log_idx as u32)?;
+            self.log_idx.store(log_idx, Ordering::SeqCst);
+            info!(
+                "[compaction]: writer of the compaction is updated! the new commands will be appended into the log idx: {}",
+                log_idx
+            );
+
+            (picked, keep_tombstones, (log_idx - 1) as u32)
+        };
+
+        let new_compaction_file_path = self.path.join(format!("{}.log", new_compaction_log_idx));
+        // the merged log is written under a temporary name, so that a crash in
+        // the middle of the compaction does not leave a partial log behind.
+        let tmp_path = new_compaction_file_path.with_extension("log.compacting");
+
+        info!(
+            "[compaction]: merging log files {:?} into log file idx {}, compaction file name {:?}",
+            picked, new_compaction_log_idx, new_compaction_file_path
+        );
+
+        // create a writer for the log entry which will include the command details of the
+        // live commands of the picked logs.
+        remove_if_exists(&tmp_path)?;
+        let mut compaction_log_writer = new_segment(&tmp_path)?;
+
+        let mut new_starting_pos = compaction_log_writer.pos;
+        let mut hint_entries = Vec::new();
+        // sources keeps the position of each copied record in the picked logs.
+        let mut sources = Vec::new();
+        // expired keeps the expired keys that are dropped, along with the
+        // position of their latest record.
+        let mut expired = Vec::new();
+        let now = now_millis();
+
+        for &idx in &picked {
+            let mut records = Vec::new();
+            read_segment(
+                &self.path.join(format!("{}.log", idx)),
+                idx,
+                TornTail::Fail,
+                |cmd, cmd_pos| records.extend(writes(cmd, cmd_pos)),
+            )?;
+
+            for (record, cmd_pos) in records {
+                let (key, expires_at, tombstone) = match &record {
+                    // only the latest record of a key is copied. An expired key is
+                    // dropped, and replaced by a tombstone if an older log that
+                    // is not merged may still hold the key.
+                    Record::Set { key, .. } | Record::BlobRef { key, .. } => {
+                        match self.key_dir.get(key).map(|e| e.value().load()) {
+                            Some(live) if live.is_at(&cmd_pos) && live.is_expired(now) => {
+                                expired.push((key.clone(), cmd_pos));
+                                if !keep_tombstones.contains(&idx) {
+                                    continue;
+                                }
+                                (key.clone(), None, true)
                             }
-                        }
-                        Request::Rm { key } => {
-                            if let Some(old_cmd) = key_dir.remove(&key) {
-                                uncompacted += old_cmd.1.len;
+                            Some(live) if live.is_at(&cmd_pos) => {
+                                (key.clone(), live.expires_at, false)
                             }
+                            _ => continue,
                         }
-                        _ => {} // no logs for Get request.
                     }
-                    starting_pos = read_so_far;
-                } else {
-                    info!("failed to get Request");
-                }
+                    Record::Remove { key }
+                        if keep_tombstones.contains(&idx) && !self.key_dir.contains_key(key) =>
+                    {
+                        (key.clone(), None, true)
+                    }
+                    _ => continue,
+                };
+
+                let record = match tombstone {
+                    true => Record::Remove { key: key.clone() },
+                    false => record,
+                };
+                // records are re-encoded instead of copied as they are, so that the
+                // records of older formats are migrated into the current format.
+                let encoded = record::encode_record(&record);
+                compaction_log_writer.write_all(&encoded)?;
+                let copied_bytes = encoded.len() as u64;
+
+                hint_entries.push(HintEntry {
+                    key,
+                    starting_pos: new_starting_pos,
+                    len: copied_bytes,
+                    expires_at,
+                    tombstone,
+                    blob: match record {
+                        Record::BlobRef { blob, .. } => Some(blob),
+                        _ => None,
+                    },
+                });
+                sources.push(cmd_pos);
+                new_starting_pos += copied_bytes;
             }
-            temp_readers.insert(*lf_idx, reader);
         }
+        compaction_log_writer.flush()?;
+        // the compacted log replaces the picked logs, so it needs to be on the
+        // disk before they are deleted.
+        compaction_log_writer.writer.get_ref().sync_all()?;
+        drop(compaction_log_writer);
 
-        let new_log_file_idx = log_files.last().unwrap_or(&0) + 1;
-        let log_idx = AtomicU64::new(new_log_file_idx as u64);
-        let new_log_file_path = path.join(format!("{}.log", new_log_file_idx));
+        // the picked logs may have no live records at all.
+        let merged = new_starting_pos > record::SEGMENT_HEADER_LEN;
+        if merged {
+            fs::rename(&tmp_path, &new_compaction_file_path)?;
+            hint::write_hint(
+                &self.path,
+                new_compaction_log_idx,
+                new_starting_pos,
+                &hint_entries,
+            )?;
+        } else {
+            remove_if_exists(&tmp_path)?;
+        }
 
-        // create a new log file.
-        let new_log_writer: BufWriterWithPos<File> = BufWriterWithPos::new(
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .append(true)
-                .open(&new_log_file_path)?,
+        // the writers are blocked by the log_writer lock, so the key_dir can be
+        // pointed to the compacted log. The records of the keys written during the
+        // compaction are dead in the compacted log already.
+        let _log_writer = self.log_writer.lock().unwrap();
+        // the picked logs are dropped from the MANIFEST before they are removed,
+        // so a crash in between leaves them as orphans to remove on open.
+        self.manifest.lock().unwrap().replace(
+            &self.path,
+            &picked,
+            merged.then_some(new_compaction_log_idx),
         )?;
-        temp_readers.insert(
-            new_log_file_idx,
-            BufReaderWithPos::new(File::open(new_log_file_path)?)?,
-        );
+        let mut usage = self.usage.lock().unwrap();
+        if merged {
+            usage.written(
+                new_compaction_log_idx,
+                new_starting_pos - record::SEGMENT_HEADER_LEN,
+            );
+        }
+        for (entry, source) in hint_entries.iter().zip(&sources) {
+            if entry.tombstone {
+                continue;
+            }
+            let cmd_pos = CommandPos {
+                log_idx: new_compaction_log_idx,
+                starting_pos: entry.starting_pos,
+                len: entry.len,
+                expires_at: entry.expires_at,
+            };
+            if !self.key_dir.relocate(&entry.key, source, cmd_pos) {
+                usage.dead(new_compaction_log_idx, entry.len);
+            }
+        }
+        // the expired keys are removed unless they are written again during the
+        // compaction; their expiries are dropped by the sweeper.
+        let mut blobs = self.blobs.lock().unwrap();
+        for (key, source) in expired {
+            if self.key_dir.remove_at(&key, &source) {
+                blobs.write(&key, None);
+            }
+        }
+        drop(blobs);
 
-        let reader = KvsReader {
-            path: path.clone(),
-            readers: RefCell::new(temp_readers),
+        // the log files pinned by snapshots are removed once the snapshots are
+        // dropped.
+        let mut pins = self.pins.lock().unwrap();
+        for &i in &picked {
+            usage.retire(i);
+            // the handles of the merged log files are not needed anymore, even
+            // if a snapshot keeps the files; it opens them again. They are
+            // released first, since a mapped file cannot be removed on Windows.
+            self.reader.release(i);
+            if pins.release(i) {
+                remove_if_exists(&self.path.join(format!("{}.log", i)))?;
+                remove_if_exists(&hint::hint_path(&self.path, i))?;
+            }
+        }
+        info!("[compaction]: merged log files {:?}", picked);
+
+        Ok(())
+    }
+
+    // collects the blob files with the highest ratio of dead bytes, the same
+    // way as the compaction of the logs: the active blob file is sealed, and
+    // the live values of the picked blob files are copied into a new blob file
+    // while the writers go on. Then the keys that are not written again in the
+    // meantime are pointed to the new blob file by logging their BlobRef
+    // records again, and the picked blob files are removed.
+    fn collect_blobs(&self) -> Result<()> {
+        let (picked, mut live, new_blob_idx) = {
+            let _log_writer = self.log_writer.lock().unwrap();
+            let mut blob_writer = self.blob_writer.lock().unwrap();
+            let blobs = self.blobs.lock().unwrap();
+
+            let picked = blobs.pick(
+                self.options.garbage_ratio,
+                self.options.max_compaction_segments,
+            );
+            if picked.is_empty() {
+                return Ok(());
+            }
+            let live = blobs.live_in(&picked);
+            (picked, live, blob_writer.reserve()?)
         };
 
-        let active_log_writer = Arc::new(Mutex::new(new_log_writer));
+        info!(
+            "[blob gc]: copying {} live values of blob files {:?} into blob file {}",
+            live.len(),
+            picked,
+            new_blob_idx
+        );
+        let new_blob_path = blob::blob_path(&self.path, new_blob_idx);
+        let tmp_path = new_blob_path.with_extension("blob.compacting");
+        remove_if_exists(&tmp_path)?;
+        let mut blob_writer = blob::new_blob_file(&tmp_path)?;
 
-        Ok(KvStore {
-            uncompacted: Arc::new(RwLock::new(uncompacted)),
-            log_writer: Arc::clone(&active_log_writer),
-            path,
-            reader,
-            key_dir: Arc::clone(&key_dir),
-            log_idx: Arc::new(log_idx),
-            tx_compaction: None,
-        })
+        // the values are read in the order they are in the blob files.
+        live.sort_by_key(|(_, blob)| (blob.blob_idx, blob.offset));
+        let mut moved = Vec::with_capacity(live.len());
+        for (key, blob) in live {
+            let value = self.reader.read_blob(&blob)?;
+            let new_blob = BlobPos {
+                blob_idx: new_blob_idx,
+                offset: blob_writer.pos,
+                ..blob
+            };
+            blob_writer.write_all(&value)?;
+            moved.push((key, blob, new_blob));
+        }
+        blob_writer.flush()?;
+        blob_writer.writer.get_ref().sync_all()?;
+        drop(blob_writer);
+        if moved.is_empty() {
+            remove_if_exists(&tmp_path)?;
+        } else {
+            fs::rename(&tmp_path, &new_blob_path)?;
+        }
+
+        // the writers are blocked while the keys are pointed to the new blob
+        // file; the keys written since the values are copied are left alone.
+        let mut writer = self.log_writer.lock().unwrap();
+        let log_idx = self.log_idx.load(Ordering::SeqCst) as u32;
+        let mut usage = self.usage.lock().unwrap();
+        let mut blobs = self.blobs.lock().unwrap();
+
+        let mut buf = Vec::new();
+        let mut relocated = Vec::with_capacity(moved.len());
+        for (key, blob, new_blob) in moved {
+            let cmd_pos = match self.key_dir.get(&key) {
+                Some(entry) if blobs.is_live(&key, &blob) => entry.value().load(),
+                _ => {
+                    blobs.dead(new_blob_idx, new_blob.len);
+                    continue;
+                }
+            };
+            let cmd = Record::BlobRef {
+                key,
+                blob: new_blob,
+                expires_at: cmd_pos.expires_at,
+            };
+            let encoded = record::encode_record(&cmd);
+            let cmd_pos = CommandPos {
+                log_idx,
+                starting_pos: writer.pos + buf.len() as u64,
+                len: encoded.len() as u64,
+                expires_at: None,
+            };
+            buf.extend_from_slice(&encoded);
+            relocated.push((cmd, cmd_pos));
+        }
+        // the picked blob files are removed next, so the records pointing to
+        // the new blob file need to be on the disk regardless of the durability.
+        write_and_sync(&mut writer, &buf, Durability::Always)?;
+        for (cmd, cmd_pos) in relocated {
+            apply_cmd(
+                &self.key_dir,
+                &self.expiries,
+                &mut usage,
+                &mut blobs,
+                cmd,
+                cmd_pos,
+            );
+        }
+
+        // the blob files are removed once the snapshots are dropped, as the
+        // snapshots may still refer to them.
+        let mut pins = self.pins.lock().unwrap();
+        for &idx in &picked {
+            blobs.retire(idx);
+            self.reader.release_blob(idx);
+            if pins.release_blob(idx) {
+                remove_if_exists(&blob::blob_path(&self.path, idx))?;
+            }
+        }
+        info!("[blob gc]: collected blob files {:?}", picked);
+        drop(pins);
+        drop(blobs);
+        drop(usage);
+        seal_if_full(
+            &mut writer,
+            &self.path,
+            &self.manifest,
+            &self.log_idx,
+            &self.options,
+        );
+
+        Ok(())
     }
 }
 
@@ -327,3 +1341,427 @@ fn log_files(p: &Path) -> Vec<u32> {
 
     y
 }
+
+// runs on the background thread of `Durability::Interval`, and syncs the active
+// log file every `interval` until the store is dropped.
+fn run_periodic_sync(
+    log_writer: &Mutex<BufWriterWithPos<File>>,
+    interval: Duration,
+    rx: Receiver<()>,
+) {
+    loop {
+        let stopped = match rx.recv_timeout(interval) {
+            Err(RecvTimeoutError::Disconnected) => true,
+            Ok(()) | Err(RecvTimeoutError::Timeout) => false,
+        };
+
+        if let Err(e) = sync_writer(log_writer) {
+            error!("[sync]: failed to sync the log file, err: {}", e);
+        }
+        if stopped {
+            break;
+        }
+    }
+}
+
+fn sync_writer(log_writer: &Mutex<BufWriterWithPos<File>>) -> Result<()> {
+    let mut writer = log_writer.lock().unwrap();
+    writer.flush()?;
+    writer.writer.get_ref().sync_data()?;
+
+    Ok(())
+}
+
+// creates a new log file, and writes the segment header into it.
+fn new_segment(path: &Path) -> Result<BufWriterWithPos<File>> {
+    let mut writer = BufWriterWithPos::new(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(true)
+            .open(path)?,
+    )?;
+    writer.write_all(&record::segment_header())?;
+    writer.flush()?;
+
+    Ok(writer)
+}
+
+// seals the active log file once it reaches the max segment size, after the
+// records are written into it. The records are written already, so a failed
+// rotation leaves the writes in the active log, and it is retried by the next
+// write.
+fn seal_if_full(
+    writer: &mut BufWriterWithPos<File>,
+    path: &Path,
+    manifest: &Mutex<Manifest>,
+    log_idx: &AtomicU64,
+    options: &KvStoreOptions,
+) {
+    if writer.pos < options.max_segment_size {
+        return;
+    }
+
+    let active_log_idx = log_idx.load(Ordering::SeqCst) as u32;
+    let new_log_idx = active_log_idx + 1;
+    match rotate(writer, path, manifest, new_log_idx) {
+        Ok(()) => {
+            log_idx.store(new_log_idx as u64, Ordering::SeqCst);
+            info!(
+                "sealed log file {}, writing into {}",
+                active_log_idx, new_log_idx
+            );
+        }
+        Err(e) => error!(
+            "failed to rotate the log file {}, err: {}",
+            active_log_idx, e
+        ),
+    }
+}
+
+// seals the active log file, and moves the writes into the new log file
+// `log_idx`, which is added to the MANIFEST before anything is written into it.
+// The sealed log is synced regardless of the durability: the logs before the
+// active one are replayed without tolerating a torn tail, so an unsynced tail
+// of a sealed log would keep the store from opening after a power loss.
+//
+// The index of a new log file is always above the indices in use, so that the
+// log files are replayed in the order of their indices.
+fn rotate(
+    writer: &mut BufWriterWithPos<File>,
+    path: &Path,
+    manifest: &Mutex<Manifest>,
+    log_idx: u32,
+) -> Result<()> {
+    writer.flush()?;
+    writer.writer.get_ref().sync_data()?;
+
+    let new_writer = new_segment(&path.join(format!("{}.log", log_idx)))?;
+    manifest.lock().unwrap().add(path, log_idx)?;
+    *writer = new_writer;
+
+    Ok(())
+}
+
+// applies a record written into the logs on the key_dir, and records the bytes
+// that became dead in the usage of the log files.
+fn apply_cmd(
+    key_dir: &KeyDir,
+    expiries: &Expiries,
+    usage: &mut SegmentUsage,
+    blobs: &mut BlobIndex,
+    cmd: Record,
+    cmd_pos: CommandPos,
+) {
+    let mut index = SegmentIndex::new(cmd_pos.log_idx);
+    index.push(cmd, cmd_pos);
+    index.apply(key_dir, expiries, usage, blobs);
+}
+
+// SegmentIndex is the writes of a log file in the order they are logged, with
+// the position of each written value, or None for a removal, along with the
+// blob of the value if it is in a blob file. It is built without touching the
+// key_dir, so the log files are read in parallel, and applied on the key_dir
+// one by one in their order.
+struct SegmentIndex {
+    log_idx: u32,
+    writes: Vec<(Vec<u8>, Option<CommandPos>, Option<BlobPos>)>,
+    // written and dead are the bytes of the log file, and how many of them are
+    // dead regardless of the other log files: tombstones and batch headers.
+    written: u64,
+    dead: u64,
+    torn_write: Option<TornWrite>,
+}
+
+impl SegmentIndex {
+    fn new(log_idx: u32) -> SegmentIndex {
+        SegmentIndex {
+            log_idx,
+            writes: Vec::new(),
+            written: 0,
+            dead: 0,
+            torn_write: None,
+        }
+    }
+
+    // reads the writes of a log file from its hint file if it has one, or from
+    // the log file otherwise.
+    fn load(dir: &Path, log_idx: u32, torn_tail: TornTail) -> Result<SegmentIndex> {
+        let log_path = dir.join(format!("{}.log", log_idx));
+        let mut index = SegmentIndex::new(log_idx);
+
+        // compacted logs come with a hint file, which is enough to rebuild the
+        // key_dir without reading the values.
+        let log_len = fs::metadata(&log_path)?.len();
+        if let Some(entries) = hint::load_hint(dir, log_idx, log_len)? {
+            for entry in entries {
+                index.written += entry.len;
+                // the Remove records kept by the compaction are live, as long
+                // as the older logs may hold the removed keys.
+                let cmd_pos = (!entry.tombstone).then_some(CommandPos {
+                    log_idx,
+                    starting_pos: entry.starting_pos,
+                    len: entry.len,
+                    expires_at: entry.expires_at,
+                });
+                index.writes.push((entry.key, cmd_pos, entry.blob));
+            }
+            return Ok(index);
+        }
+
+        index.torn_write = read_segment(&log_path, log_idx, torn_tail, |cmd, cmd_pos| {
+            index.push(cmd, cmd_pos)
+        })?;
+        Ok(index)
+    }
+
+    fn push(&mut self, cmd: Record, cmd_pos: CommandPos) {
+        self.written += cmd_pos.len;
+        if let Record::Batch(_) = cmd {
+            self.dead += record::BATCH_HEADER_LEN as u64;
+        }
+
+        for (cmd, cmd_pos) in writes(cmd, cmd_pos) {
+            match cmd {
+                Record::Set {
+                    key, expires_at, ..
+                } => self.writes.push((
+                    key,
+                    Some(CommandPos {
+                        expires_at,
+                        ..cmd_pos
+                    }),
+                    None,
+                )),
+                Record::BlobRef {
+                    key,
+                    blob,
+                    expires_at,
+                } => self.writes.push((
+                    key,
+                    Some(CommandPos {
+                        expires_at,
+                        ..cmd_pos
+                    }),
+                    Some(blob),
+                )),
+                // the remove record itself is dead as soon as it is written.
+                Record::Remove { key } => {
+                    self.dead += cmd_pos.len;
+                    self.writes.push((key, None, None));
+                }
+                // batches are never nested.
+                Record::Batch(_) => {}
+            }
+        }
+    }
+
+    fn apply(
+        self,
+        key_dir: &KeyDir,
+        expiries: &Expiries,
+        usage: &mut SegmentUsage,
+        blobs: &mut BlobIndex,
+    ) {
+        usage.written(self.log_idx, self.written);
+        usage.dead(self.log_idx, self.dead);
+
+        for (key, cmd_pos, blob) in self.writes {
+            blobs.write(&key, blob);
+            let old_cmd = match cmd_pos {
+                Some(cmd_pos) => {
+                    if let Some(expires_at) = cmd_pos.expires_at {
+                        expiries.insert((expires_at, key.clone()));
+                    }
+                    key_dir.replace(&key, cmd_pos)
+                }
+                None => key_dir.remove(&key),
+            };
+            if let Some(old_cmd) = old_cmd {
+                usage.dead(old_cmd.log_idx, old_cmd.len);
+            }
+        }
+    }
+}
+
+// returns the Set and Remove records of a record along with their positions; the
+// writes of a batch are complete records on their own, placed one after the
+// other following the batch header.
+fn writes(cmd: Record, cmd_pos: CommandPos) -> Vec<(Record, CommandPos)> {
+    let records = match cmd {
+        Record::Batch(records) => records,
+        cmd => return vec![(cmd, cmd_pos)],
+    };
+
+    let mut starting_pos = cmd_pos.starting_pos + record::BATCH_HEADER_LEN as u64;
+    records
+        .into_iter()
+        .map(|record| {
+            let len = record.encoded_len() as u64;
+            let record_pos = CommandPos {
+                log_idx: cmd_pos.log_idx,
+                starting_pos,
+                len,
+                expires_at: None,
+            };
+            starting_pos += len;
+            (record, record_pos)
+        })
+        .collect()
+}
+
+// TornTail decides how read_segment treats a record that does not match its
+// checksum, or ends before its length, when no valid record follows it. A
+// damaged record followed by valid ones is never a torn write, and is always
+// reported as KvsError::Corruption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TornTail {
+    // the tail of the segment is truncated; such a record at the tail of the
+    // active segment is a torn write of a crash.
+    Truncate,
+    // the tail of the segment is skipped, but left on the disk.
+    Ignore,
+    // the record is reported as KvsError::Corruption, as in a sealed segment.
+    Fail,
+}
+
+// reads the records of the log file in order, passing each of them to `f` along
+// with its position. It returns the torn write at the tail of the log file, if
+// any.
+fn read_segment<F>(
+    path: &Path,
+    log_idx: u32,
+    torn_tail: TornTail,
+    mut f: F,
+) -> Result<Option<TornWrite>>
+where
+    F: FnMut(Record, CommandPos),
+{
+    let mut file = File::open(path)?;
+    let mut buffer = Vec::new();
+    (&mut file)
+        .take(record::SEGMENT_HEADER_LEN)
+        .read_to_end(&mut buffer)?;
+
+    let version = record::segment_version(&buffer)?;
+    if version == record::LEGACY_VERSION {
+        // the legacy records have no framing, so they are parsed from the whole
+        // log file at once.
+        file.read_to_end(&mut buffer)?;
+        let mut starting_pos = 0;
+        let mut parser = KvReqParser::new(&buffer);
+
+        while let Some(v) = parser.next() {
+            let read_so_far = parser.read_so_far() as u64;
+            match record::decode_legacy(v) {
+                Some(cmd) => {
+                    let cmd_pos = CommandPos {
+                        log_idx,
+                        starting_pos,
+                        len: read_so_far - starting_pos,
+                        expires_at: None,
+                    };
+                    f(cmd, cmd_pos);
+                }
+                None => warn!("failed to parse a legacy record in {:?}", path),
+            }
+            starting_pos = read_so_far;
+        }
+
+        return Ok(None);
+    }
+
+    // the log is read in chunks; buffer holds the bytes from `offset` on, and
+    // the records are decoded from `start` in it.
+    buffer.clear();
+    let mut offset = record::SEGMENT_HEADER_LEN;
+    let mut start = 0;
+    let mut eof = false;
+    while !(eof && start == buffer.len()) {
+        match record::decode_record(version, &buffer[start..]) {
+            Frame::Complete { record: cmd, len } => {
+                let cmd_pos = CommandPos {
+                    log_idx,
+                    starting_pos: offset + start as u64,
+                    len: len as u64,
+                    expires_at: None,
+                };
+                f(cmd, cmd_pos);
+                start += len;
+            }
+            // a record may span many chunks; the buffer grows to fit it.
+            Frame::Incomplete if !eof => {
+                buffer.drain(..start);
+                offset += start as u64;
+                start = 0;
+                let n = (&mut file)
+                    .take(REPLAY_CHUNK_SIZE)
+                    .read_to_end(&mut buffer)?;
+                eof = n == 0;
+            }
+            Frame::Incomplete | Frame::Corrupted if torn_tail != TornTail::Fail => {
+                file.read_to_end(&mut buffer)?;
+                if record::has_valid_record_after(version, &buffer[start..]) {
+                    return Err(KvsError::Corruption(log_idx, offset + start as u64));
+                }
+                let torn_write = TornWrite {
+                    log_idx,
+                    offset: offset + start as u64,
+                    bytes: (buffer.len() - start) as u64,
+                    records: record::count_records(version, &buffer[start..]),
+                };
+                warn!(
+                    "torn write in {:?}, discarding {} bytes ({} records) from offset {}",
+                    path, torn_write.bytes, torn_write.records, torn_write.offset
+                );
+                if torn_tail == TornTail::Truncate {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(path)?
+                        .set_len(torn_write.offset)?;
+                }
+                return Ok(Some(torn_write));
+            }
+            Frame::Incomplete | Frame::Corrupted => {
+                return Err(KvsError::Corruption(log_idx, offset + start as u64))
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns the current time as milliseconds since the unix epoch, which is the
+/// unit of the expiry times.
+pub(super) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+pub(super) fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|t| t <= now)
+}
+
+fn unexpected_record(key: &[u8]) -> KvsError {
+    KvsError::UnexpectedCommandType(String::from_utf8_lossy(key).into_owned())
+}
+
+fn record_expired(record: &Record, now: u64) -> bool {
+    match record {
+        Record::Set { expires_at, .. } | Record::BlobRef { expires_at, .. } => {
+            is_expired(*expires_at, now)
+        }
+        _ => false,
+    }
+}
+
+pub(super) fn remove_if_exists(p: &Path) -> io::Result<()> {
+    fs::remove_file(p).or_else(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })
+}
@@ -0,0 +1,128 @@
+use crate::Result;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use std::{
+    collections::BTreeSet,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+// The MANIFEST lists the live log files of the store, in the order they are
+// replayed. A log file is created before it is added to the MANIFEST, and removed
+// only after it is dropped from the MANIFEST, so a crash at any point leaves the
+// store in the state of the last MANIFEST; the files which are not in it are
+// leftovers of an unfinished compaction, and they are removed on open.
+//
+// The MANIFEST is a single line of JSON, replaced atomically by writing a
+// temporary file and renaming it over the previous one.
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Manifest is the list of the live log files of a store.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    // segments keeps the indices of the live log files in the order they are
+    // replayed, which is the order of the indices as well; a compacted log is
+    // given an index after the logs it merges.
+    segments: Vec<u32>,
+}
+
+impl Manifest {
+    /// Creates a manifest of the given log files.
+    pub fn new(mut segments: Vec<u32>) -> Manifest {
+        segments.sort_unstable();
+        Manifest { segments }
+    }
+
+    /// Loads the MANIFEST of the store in `dir`, or returns `None` if the store
+    /// is created by a version without a MANIFEST.
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+    }
+
+    /// Returns the live log files in the order they are replayed.
+    pub fn segments(&self) -> &[u32] {
+        &self.segments
+    }
+
+    /// Adds a new active log file, which is placed after all the others.
+    pub fn add(&mut self, dir: &Path, log_idx: u32) -> Result<()> {
+        self.segments.push(log_idx);
+        self.store(dir)
+    }
+
+    /// Replaces the log files merged by a compaction with the compacted log, if
+    /// the compaction has written one.
+    pub fn replace(
+        &mut self,
+        dir: &Path,
+        merged: &BTreeSet<u32>,
+        compacted: Option<u32>,
+    ) -> Result<()> {
+        self.segments.retain(|idx| !merged.contains(idx));
+        if let Some(compacted) = compacted {
+            let pos = self.segments.partition_point(|idx| *idx < compacted);
+            self.segments.insert(pos, compacted);
+        }
+        self.store(dir)
+    }
+
+    // writes the MANIFEST into `dir`, replacing the previous one atomically.
+    fn store(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(MANIFEST_FILE);
+        let tmp_path = path.with_extension("tmp");
+
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        fs::rename(tmp_path, path)?;
+        // the rename itself is durable only once the directory is synced.
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Removes the files of the store in `dir` which do not belong to a live log
+    /// file: the log and hint files left behind by a compaction, and the
+    /// temporary files of an unfinished write. Other files are not touched.
+    pub fn remove_orphans(&self, dir: &Path) -> Result<()> {
+        let live: BTreeSet<u32> = self.segments.iter().copied().collect();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if is_orphan(&path, &live) {
+                info!("removing orphaned file {:?}", path);
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_orphan(path: &Path, live: &BTreeSet<u32>) -> bool {
+    let name = match path.file_name().and_then(OsStr::to_str) {
+        Some(name) => name,
+        None => return false,
+    };
+    if name == "MANIFEST.tmp" {
+        return true;
+    }
+
+    let (idx, extension) = match name.split_once('.') {
+        Some((idx, extension)) => (idx, extension),
+        None => return false,
+    };
+    match (idx.parse::<u32>(), extension) {
+        (Ok(idx), "log" | "hint") => !live.contains(&idx),
+        (Ok(_), "log.compacting" | "hint.tmp" | "blob.compacting") => true,
+        _ => false,
+    }
+}
@@ -1,14 +1,110 @@
 use crate::Result;
+use std::{
+    ops::{Bound, RangeBounds},
+    time::Duration,
+};
 
+mod batch;
+mod blob;
+mod cache;
+mod condition;
+mod hint;
+mod keydir;
 mod kv;
+mod manifest;
+mod options;
+mod reader;
+mod record;
 mod sled;
-pub use self::kv::CommandPos;
-pub use self::kv::KvStore;
-pub use self::kv::KvsReader;
+mod snapshot;
+mod transaction;
+mod usage;
+mod write_queue;
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::cache::{CacheStats, CachedEngine};
+pub use self::condition::Condition;
+pub use self::kv::{Durability, KvStore, TornWrite};
+pub use self::options::{KvStoreOptions, DEFAULT_MAX_SEGMENT_SIZE};
 pub use self::sled::SledKvsEngine;
+pub use self::snapshot::Snapshot;
+pub use self::transaction::{ReadSet, Transaction};
 
+/// KvsEngine stores arbitrary bytes as keys and values. Strings can be passed
+/// wherever bytes are expected, and `get` and `scan` return strings for the
+/// values known to be UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Returns the value of the key as a string. It fails with
+    /// `KvsError::Utf8` if the value is not valid UTF-8.
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Sets the value of the key, which expires once `ttl` passes. An expired
+    /// key behaves as if it was removed.
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()>;
+
+    /// Returns the remaining time to live of the key, or `None` if the key
+    /// never expires. It fails with `KvsError::KeyNotFound` if there is no such key.
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>>;
+
+    /// Drops the expiry of the key, so that it never expires.
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Sets the value of the key only if the key satisfies `condition`, which is
+    /// checked atomically with the write. It fails with
+    /// `KvsError::ConditionFailed` otherwise.
+    fn set_if(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        condition: Condition,
+    ) -> Result<()>;
+
+    /// Applies the writes of the batch atomically.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Begins an optimistic transaction on the engine.
+    fn transaction(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+    /// Applies the writes of the batch atomically, only if every key in `reads`
+    /// still has the value it is mapped to. It fails with
+    /// `KvsError::TransactionConflict` otherwise. This is the commit of a
+    /// `Transaction`.
+    fn commit_transaction(&self, reads: ReadSet, batch: WriteBatch) -> Result<()>;
+
+    /// Returns the key/value pairs whose keys are in `range` in key order, up
+    /// to `limit` pairs.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Same as `scan_bytes`, for the keys and values known to be UTF-8. Strings
+    /// are ordered by their bytes, so the range covers the same keys.
+    fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let range = (
+            range.start_bound().map(|key| key.clone().into_bytes()),
+            range.end_bound().map(|key| key.clone().into_bytes()),
+        );
+        self.scan_bytes::<(Bound<Vec<u8>>, Bound<Vec<u8>>)>(range, limit)?
+            .into_iter()
+            .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+            .collect()
+    }
 }
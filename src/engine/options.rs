@@ -0,0 +1,154 @@
+use super::kv::Durability;
+use crate::{KvsError, Result};
+
+/// The size of the active log file in bytes after which it is sealed, and the
+/// writes move to a new log file.
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const DEFAULT_GARBAGE_RATIO: f64 = 0.5;
+const DEFAULT_MAX_COMPACTION_SEGMENTS: usize = 8;
+const DEFAULT_READER_CACHE_SIZE: usize = 64;
+
+/// KvStoreOptions configures a KvStore opened by `KvStore::open_with`.
+///
+/// ```no_run
+/// # use kvs::{Durability, KvStore, KvStoreOptions};
+/// # use std::time::Duration;
+/// let options = KvStoreOptions::new()
+///     .durability(Durability::Interval(Duration::from_millis(100)))
+///     .max_segment_size(64 * 1024 * 1024);
+/// let store = KvStore::open_with("data", options)?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct KvStoreOptions {
+    pub(super) compaction_threshold: u64,
+    pub(super) garbage_ratio: f64,
+    pub(super) max_compaction_segments: usize,
+    pub(super) max_segment_size: u64,
+    pub(super) durability: Durability,
+    pub(super) reader_cache_size: usize,
+    pub(super) read_only: bool,
+    pub(super) blob_threshold: Option<u64>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            garbage_ratio: DEFAULT_GARBAGE_RATIO,
+            max_compaction_segments: DEFAULT_MAX_COMPACTION_SEGMENTS,
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            durability: Durability::Never,
+            reader_cache_size: DEFAULT_READER_CACHE_SIZE,
+            read_only: false,
+            blob_threshold: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates the default options.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Sets the number of dead bytes in the log files worth compacting that
+    /// triggers a compaction; 1 MiB by default.
+    pub fn compaction_threshold(mut self, bytes: u64) -> KvStoreOptions {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the ratio of dead bytes that makes a log file worth compacting,
+    /// between 0 and 1; 0.5 by default. The log files holding more live data
+    /// are left alone.
+    pub fn garbage_ratio(mut self, ratio: f64) -> KvStoreOptions {
+        self.garbage_ratio = ratio;
+        self
+    }
+
+    /// Sets the number of log files merged by a compaction at most; 8 by
+    /// default.
+    pub fn max_compaction_segments(mut self, segments: usize) -> KvStoreOptions {
+        self.max_compaction_segments = segments;
+        self
+    }
+
+    /// Sets the size of the active log file after which it is sealed;
+    /// `DEFAULT_MAX_SEGMENT_SIZE` by default.
+    pub fn max_segment_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// Sets when the writes are synced to the disk; `Durability::Never` by
+    /// default.
+    pub fn durability(mut self, durability: Durability) -> KvStoreOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the number of log files kept open for reading, shared by every
+    /// clone of the store; 64 by default.
+    pub fn reader_cache_size(mut self, readers: usize) -> KvStoreOptions {
+        self.reader_cache_size = readers;
+        self
+    }
+
+    /// Opens the store without modifying anything on the disk: the writes fail
+    /// with `KvsError::ReadOnly`, and neither the compaction nor the expiry
+    /// sweeper runs. The store must not be written by another process while it
+    /// is open read-only.
+    pub fn read_only(mut self, read_only: bool) -> KvStoreOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Stores the values of at least `bytes` bytes in blob files, and logs only
+    /// their positions, so that the compaction does not copy the large values
+    /// along with the small ones. The blob files are collected on their own,
+    /// by the same garbage ratio and threshold as the logs. Every value is
+    /// logged as it is by default.
+    pub fn blob_threshold(mut self, bytes: u64) -> KvStoreOptions {
+        self.blob_threshold = Some(bytes);
+        self
+    }
+
+    pub(super) fn validate(&self) -> Result<()> {
+        if !(self.garbage_ratio > 0.0 && self.garbage_ratio <= 1.0) {
+            return Err(KvsError::InvalidOption(format!(
+                "garbage ratio must be in (0, 1], got {}",
+                self.garbage_ratio
+            )));
+        }
+        if self.max_compaction_segments == 0 {
+            return Err(KvsError::InvalidOption(
+                "max compaction segments must be at least 1".to_string(),
+            ));
+        }
+        if self.max_segment_size == 0 {
+            return Err(KvsError::InvalidOption(
+                "max segment size must be at least 1".to_string(),
+            ));
+        }
+        if self.reader_cache_size == 0 {
+            return Err(KvsError::InvalidOption(
+                "reader cache size must be at least 1".to_string(),
+            ));
+        }
+        if self.blob_threshold == Some(0) {
+            return Err(KvsError::InvalidOption(
+                "blob threshold must be at least 1".to_string(),
+            ));
+        }
+        if let Durability::Interval(interval) = self.durability {
+            if interval.is_zero() {
+                return Err(KvsError::InvalidOption(
+                    "sync interval must not be zero".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+use super::{
+    blob::{self, BlobPos},
+    kv::CommandPos,
+    record::{self, Frame, Record},
+};
+use crate::{KvsError, Result};
+use log::warn;
+use memmap2::Mmap;
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// KvsReader reads the records of a store from its log files. It is cheap to
+/// clone, and every clone shares the same open log files, so the clones of a
+/// KvStore, its snapshots and the compaction read from many threads without
+/// reopening the files or seeking a shared cursor.
+///
+/// The sealed log files never change, so they are memory-mapped, and reading a
+/// record from them is a lookup into the mapping. The active log file grows
+/// while it is read, so it is read with positional reads instead. The blob files
+/// are read the same way, through a pool of their own.
+#[derive(Clone)]
+pub struct KvsReader {
+    pub path: PathBuf,
+    pool: Arc<SegmentPool>,
+    blobs: Arc<SegmentPool>,
+}
+
+impl KvsReader {
+    /// Creates a reader of the log and blob files in `path`, keeping
+    /// `capacity` files of each open at most. `active_log` and `active_blob`
+    /// are the indices of the log file and the blob file that are written, or
+    /// `None` if the store is read-only and every file is sealed.
+    pub fn new(
+        path: PathBuf,
+        capacity: usize,
+        active_log: Option<Arc<AtomicU64>>,
+        active_blob: Option<Arc<AtomicU64>>,
+    ) -> KvsReader {
+        KvsReader {
+            path,
+            pool: Arc::new(SegmentPool::new(FileKind::Log, capacity, active_log)),
+            blobs: Arc::new(SegmentPool::new(FileKind::Blob, capacity, active_blob)),
+        }
+    }
+
+    /// Reads and decodes the record at `cmd_pos`. The checksum of the record is
+    /// verified unless the record is in a legacy segment. A BlobRef record is
+    /// returned as a Set record along with its value read from the blob file.
+    pub fn read_record(&self, cmd_pos: &CommandPos) -> Result<Record> {
+        match self.read_log_record(cmd_pos)? {
+            Record::BlobRef {
+                key,
+                blob,
+                expires_at,
+            } => Ok(Record::Set {
+                key,
+                value: self.read_blob(&blob)?,
+                expires_at,
+            }),
+            record => Ok(record),
+        }
+    }
+
+    /// Reads the value in a blob file, and verifies its checksum.
+    pub fn read_blob(&self, blob: &BlobPos) -> Result<Vec<u8>> {
+        let file = self.handle(&self.blobs, blob.blob_idx)?;
+        let value = match &file.map {
+            Some(map) => {
+                let start = (blob.offset as usize).min(map.len());
+                let end = (start + blob.len as usize).min(map.len());
+                map[start..end].to_vec()
+            }
+            None => file.read(blob.offset, blob.len)?,
+        };
+        if value.len() as u64 != blob.len || crc32fast::hash(&value) != blob.crc {
+            return Err(KvsError::BlobCorruption(blob.blob_idx, blob.offset));
+        }
+        Ok(value)
+    }
+
+    fn read_log_record(&self, cmd_pos: &CommandPos) -> Result<Record> {
+        let segment = self.handle(&self.pool, cmd_pos.log_idx)?;
+        match &segment.map {
+            Some(map) => {
+                let start = (cmd_pos.starting_pos as usize).min(map.len());
+                let end = (start + cmd_pos.len as usize).min(map.len());
+                decode(segment.version, &map[start..end], cmd_pos)
+            }
+            None => {
+                let buf = segment.read(cmd_pos.starting_pos, cmd_pos.len)?;
+                decode(segment.version, &buf, cmd_pos)
+            }
+        }
+    }
+
+    /// Drops the handle of a log file that is removed, or about to be removed.
+    /// The reads already holding the handle finish on it, and the file is
+    /// closed once the last of them is done.
+    pub fn release(&self, log_idx: u32) {
+        self.pool.segments.lock().unwrap().handles.remove(&log_idx);
+    }
+
+    /// Same as `release`, for a blob file.
+    pub fn release_blob(&self, blob_idx: u32) {
+        self.blobs
+            .segments
+            .lock()
+            .unwrap()
+            .handles
+            .remove(&blob_idx);
+    }
+
+    // returns the handle of the file, opening it if it is not in the pool. A
+    // file opened while it is active is opened again once it is sealed, so that
+    // it is mapped.
+    fn handle(&self, pool: &SegmentPool, idx: u32) -> Result<Arc<SegmentHandle>> {
+        let sealed = pool.is_sealed(idx);
+        let cached = pool.segments.lock().unwrap().get(idx);
+        match cached {
+            Some(segment) if segment.map.is_some() || !sealed => return Ok(segment),
+            Some(_) => {
+                pool.segments.lock().unwrap().handles.remove(&idx);
+            }
+            None => {}
+        }
+
+        // the file is opened without holding the lock, so two threads may open
+        // the same file; the handle inserted first is kept.
+        let segment = Arc::new(SegmentHandle::open(&self.path, pool.kind, idx, sealed)?);
+        Ok(pool
+            .segments
+            .lock()
+            .unwrap()
+            .insert(idx, segment, pool.capacity))
+    }
+}
+
+fn decode(version: u32, buf: &[u8], cmd_pos: &CommandPos) -> Result<Record> {
+    if version == record::LEGACY_VERSION {
+        return record::decode_legacy(buf).ok_or_else(|| {
+            KvsError::KvsDeserializer(
+                String::from_utf8_lossy(buf).into_owned(),
+                "invalid legacy record".to_string(),
+            )
+        });
+    }
+
+    match record::decode_record(version, buf) {
+        Frame::Complete { record, len } if len == buf.len() => Ok(record),
+        _ => Err(KvsError::Corruption(cmd_pos.log_idx, cmd_pos.starting_pos)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileKind {
+    Log,
+    Blob,
+}
+
+// SegmentPool is the set of the open log or blob files shared by the clones of
+// a KvsReader.
+struct SegmentPool {
+    kind: FileKind,
+    capacity: usize,
+    active: Option<Arc<AtomicU64>>,
+    segments: Mutex<Segments>,
+}
+
+impl SegmentPool {
+    fn new(kind: FileKind, capacity: usize, active: Option<Arc<AtomicU64>>) -> SegmentPool {
+        SegmentPool {
+            kind,
+            capacity,
+            active,
+            segments: Mutex::new(Segments::default()),
+        }
+    }
+
+    // returns whether the file is not written anymore. The compacted logs and
+    // the blob files written by the blob GC are complete before they are read,
+    // and the files are sealed in the order of their indices.
+    fn is_sealed(&self, idx: u32) -> bool {
+        match &self.active {
+            Some(active) => (idx as u64) < active.load(Ordering::SeqCst),
+            None => true,
+        }
+    }
+}
+
+// Segments keeps the open log files by their index, along with the time they
+// are last used at; the least recently used one is closed to make room for a
+// new one.
+#[derive(Default)]
+struct Segments {
+    handles: HashMap<u32, (Arc<SegmentHandle>, u64)>,
+    clock: u64,
+}
+
+impl Segments {
+    fn get(&mut self, log_idx: u32) -> Option<Arc<SegmentHandle>> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.handles.get_mut(&log_idx).map(|(segment, last_used)| {
+            *last_used = clock;
+            Arc::clone(segment)
+        })
+    }
+
+    fn insert(
+        &mut self,
+        log_idx: u32,
+        segment: Arc<SegmentHandle>,
+        capacity: usize,
+    ) -> Arc<SegmentHandle> {
+        if let Some(segment) = self.get(log_idx) {
+            return segment;
+        }
+
+        if self.handles.len() >= capacity {
+            let lru = self
+                .handles
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(idx, _)| *idx);
+            if let Some(lru) = lru {
+                self.handles.remove(&lru);
+            }
+        }
+        self.handles
+            .insert(log_idx, (Arc::clone(&segment), self.clock));
+        segment
+    }
+}
+
+// SegmentHandle is an open log or blob file along with its format version,
+// and the mapping of the file if it is sealed. It is only read with positional
+// reads, so it has no cursor to share between threads.
+struct SegmentHandle {
+    file: File,
+    map: Option<Mmap>,
+    version: u32,
+}
+
+impl SegmentHandle {
+    fn open(dir: &Path, kind: FileKind, idx: u32, sealed: bool) -> Result<SegmentHandle> {
+        let path = match kind {
+            FileKind::Log => dir.join(format!("{}.log", idx)),
+            FileKind::Blob => blob::blob_path(dir, idx),
+        };
+        let file = File::open(&path)?;
+        let mut header = vec![0; record::SEGMENT_HEADER_LEN as usize];
+        let n = read_full_at(&file, &mut header, 0)?;
+        header.truncate(n);
+        let version = match kind {
+            FileKind::Log => record::segment_version(&header)?,
+            FileKind::Blob => {
+                blob::check_header(idx, &header)?;
+                blob::BLOB_VERSION
+            }
+        };
+
+        let map = if sealed {
+            // SAFETY: a sealed log or blob file is never written or truncated
+            // again; it is only removed, which leaves the mapping valid.
+            match unsafe { Mmap::map(&file) } {
+                Ok(map) => Some(map),
+                Err(e) => {
+                    warn!(
+                        "failed to map {:?}, reading it from the file, err: {}",
+                        path, e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(SegmentHandle { version, file, map })
+    }
+
+    // reads `len` bytes at `pos`, or fewer if the file ends before.
+    fn read(&self, pos: u64, len: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len as usize];
+        let n = read_full_at(&self.file, &mut buf, pos)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+}
+
+// fills `buf` with the bytes of the file at `offset`, without moving the cursor
+// of the file, and returns the number of bytes read, which is less than the
+// length of `buf` only at the end of the file.
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match read_at(file, &mut buf[read..], offset + read as u64) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
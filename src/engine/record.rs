@@ -0,0 +1,386 @@
+use super::blob::BlobPos;
+use crate::{KvsError, Result};
+use kvs_protocol::{deserializer::deserialize as kvs_deserialize, request::Request};
+
+// On-disk format of the records stored in the log files.
+//
+// Each log file starts with a segment header, which is the SEGMENT_MAGIC followed by
+// the format version of the segment as u32 (little endian). The records of the
+// current format (FORMAT_VERSION) are laid out as:
+//
+// | crc32 (u32) | key length (u32) | value length (u32) | type (u8) | key | value |
+//
+// where all integers are little endian and the crc32 is computed over everything
+// following it in the record.
+//
+// A Set record with an expiry has its own type, and its value is prefixed with
+// the expiry time as milliseconds since the unix epoch (u64).
+//
+// A BlobRef record is a Set record whose value is in a blob file; its value is
+// the position of the value, laid out as
+//
+// | blob index (u32) | offset (u64) | length (u64) | crc32 of the value (u32) |
+//
+// prefixed with the expiry time, if the key has one.
+//
+// A batch of writes is a single record whose key is empty and whose value is the
+// Set and Remove records of the batch, each encoded as a complete record. So, the
+// checksum of the batch covers all of its writes, and a write of a batch can be
+// read on its own by its position.
+//
+// Older formats are still readable, so that the logs written by the previous
+// versions can be loaded and migrated by the compaction:
+//  - LEGACY_VERSION: log files without a segment header, including the requests
+//    serialized by kvs_protocol without any framing.
+//  - FRAMED_TEXT_VERSION: the requests serialized by kvs_protocol, framed as
+//    | crc32 (u32) | payload length (u32) | payload |.
+
+pub const SEGMENT_MAGIC: [u8; 4] = *b"KVSL";
+pub const LEGACY_VERSION: u32 = 0;
+pub const FRAMED_TEXT_VERSION: u32 = 1;
+pub const FORMAT_VERSION: u32 = 2;
+pub const SEGMENT_HEADER_LEN: u64 = 8;
+
+const FRAMED_TEXT_HEADER_LEN: usize = 8;
+const RECORD_HEADER_LEN: usize = 13;
+/// The length of a batch record without the records of its writes.
+pub const BATCH_HEADER_LEN: usize = RECORD_HEADER_LEN;
+
+const RECORD_TYPE_SET: u8 = 1;
+const RECORD_TYPE_REMOVE: u8 = 2;
+const RECORD_TYPE_BATCH: u8 = 3;
+const RECORD_TYPE_SET_WITH_EXPIRY: u8 = 4;
+const RECORD_TYPE_BLOB: u8 = 5;
+const RECORD_TYPE_BLOB_WITH_EXPIRY: u8 = 6;
+
+const EXPIRY_LEN: usize = 8;
+const BLOB_POS_LEN: usize = 24;
+
+/// Record is a single entry of the log.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// `expires_at` is the expiry time of the key as milliseconds since the unix
+    /// epoch, if it has one.
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// BlobRef is a Set record whose value is stored in a blob file. It is
+    /// only found in the logs; the reads return it as a Set record.
+    BlobRef {
+        key: Vec<u8>,
+        blob: BlobPos,
+        expires_at: Option<u64>,
+    },
+    /// Batch includes only Set, BlobRef and Remove records.
+    Batch(Vec<Record>),
+}
+
+impl Record {
+    /// Returns the key of a Set or Remove record; batches have no key.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Record::Set { key, .. } | Record::Remove { key } | Record::BlobRef { key, .. } => key,
+            Record::Batch(_) => &[],
+        }
+    }
+
+    /// Returns the length of the record once it is encoded.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Record::Set {
+                key,
+                value,
+                expires_at,
+            } => {
+                let expiry_len = expires_at.map_or(0, |_| EXPIRY_LEN);
+                RECORD_HEADER_LEN + key.len() + expiry_len + value.len()
+            }
+            Record::Remove { key } => RECORD_HEADER_LEN + key.len(),
+            Record::BlobRef {
+                key, expires_at, ..
+            } => {
+                let expiry_len = expires_at.map_or(0, |_| EXPIRY_LEN);
+                RECORD_HEADER_LEN + key.len() + expiry_len + BLOB_POS_LEN
+            }
+            Record::Batch(records) => {
+                BATCH_HEADER_LEN + records.iter().map(Record::encoded_len).sum::<usize>()
+            }
+        }
+    }
+
+    // converts a request of the legacy formats into a record; `Get` requests are
+    // never logged.
+    fn from_request(req: Request) -> Option<Record> {
+        match req {
+            Request::Set { key, val } => Some(Record::Set {
+                key: key.into_bytes(),
+                value: val.into_bytes(),
+                expires_at: None,
+            }),
+            Request::Rm { key } => Some(Record::Remove {
+                key: key.into_bytes(),
+            }),
+            Request::Get { .. } => None,
+        }
+    }
+}
+
+/// Frame is the result of decoding a record from the beginning of a buffer.
+#[derive(Debug)]
+pub enum Frame {
+    /// A record whose bytes match its checksum. `len` is the length of the
+    /// whole record including its header.
+    Complete { record: Record, len: usize },
+    /// The buffer ends before the end of the record.
+    Incomplete,
+    /// The bytes of the record do not match its checksum, or cannot be decoded.
+    Corrupted,
+}
+
+pub fn segment_header() -> [u8; SEGMENT_HEADER_LEN as usize] {
+    let mut header = [0; SEGMENT_HEADER_LEN as usize];
+    header[..4].copy_from_slice(&SEGMENT_MAGIC);
+    header[4..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Returns the format version of a segment from its first bytes, or
+/// LEGACY_VERSION if the segment has no header.
+pub fn segment_version(buf: &[u8]) -> Result<u32> {
+    if buf.len() < SEGMENT_HEADER_LEN as usize || buf[..4] != SEGMENT_MAGIC {
+        return Ok(LEGACY_VERSION);
+    }
+
+    match read_u32(&buf[4..]) {
+        v @ (FRAMED_TEXT_VERSION | FORMAT_VERSION) => Ok(v),
+        v => Err(KvsError::UnsupportedFormat(v)),
+    }
+}
+
+pub fn encode_record(record: &Record) -> Vec<u8> {
+    let batch_value;
+    let expiring_value;
+    let blob_value;
+    let (record_type, key, value) = match record {
+        Record::Set {
+            key,
+            value,
+            expires_at: None,
+        } => (RECORD_TYPE_SET, &key[..], &value[..]),
+        Record::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        } => {
+            expiring_value = [&expires_at.to_le_bytes()[..], &value[..]].concat();
+            (RECORD_TYPE_SET_WITH_EXPIRY, &key[..], &expiring_value[..])
+        }
+        Record::Remove { key } => (RECORD_TYPE_REMOVE, &key[..], &[][..]),
+        Record::BlobRef {
+            key,
+            blob,
+            expires_at,
+        } => {
+            let mut value = Vec::with_capacity(EXPIRY_LEN + BLOB_POS_LEN);
+            if let Some(expires_at) = expires_at {
+                value.extend_from_slice(&expires_at.to_le_bytes());
+            }
+            value.extend_from_slice(&blob.blob_idx.to_le_bytes());
+            value.extend_from_slice(&blob.offset.to_le_bytes());
+            value.extend_from_slice(&blob.len.to_le_bytes());
+            value.extend_from_slice(&blob.crc.to_le_bytes());
+            blob_value = value;
+            let record_type = match expires_at {
+                Some(_) => RECORD_TYPE_BLOB_WITH_EXPIRY,
+                None => RECORD_TYPE_BLOB,
+            };
+            (record_type, &key[..], &blob_value[..])
+        }
+        Record::Batch(records) => {
+            batch_value = records.iter().flat_map(encode_record).collect::<Vec<u8>>();
+            (RECORD_TYPE_BATCH, &[][..], &batch_value[..])
+        }
+    };
+
+    let mut buf = Vec::with_capacity(RECORD_HEADER_LEN + key.len() + value.len());
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buf.push(record_type);
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+/// Decodes the record at the beginning of `buf`, written in the given format
+/// version. Segments of LEGACY_VERSION have no framing; use `decode_legacy` for
+/// them.
+pub fn decode_record(version: u32, buf: &[u8]) -> Frame {
+    let len = match record_len(version, buf) {
+        Some(len) if len <= buf.len() => len,
+        _ => return Frame::Incomplete,
+    };
+
+    let record = if version == FRAMED_TEXT_VERSION {
+        let payload = &buf[FRAMED_TEXT_HEADER_LEN..len];
+        if crc32fast::hash(payload) != read_u32(buf) {
+            return Frame::Corrupted;
+        }
+        decode_legacy(payload)
+    } else {
+        if crc32fast::hash(&buf[4..len]) != read_u32(buf) {
+            return Frame::Corrupted;
+        }
+        decode_binary(&buf[..len])
+    };
+
+    match record {
+        Some(record) => Frame::Complete { record, len },
+        None => Frame::Corrupted,
+    }
+}
+
+/// Decodes a request serialized by kvs_protocol, as stored by the legacy formats.
+pub fn decode_legacy(payload: &[u8]) -> Option<Record> {
+    let s = std::str::from_utf8(payload).ok()?;
+    kvs_deserialize::<Request>(s)
+        .ok()
+        .and_then(Record::from_request)
+}
+
+/// Counts the records in a torn tail of a segment. The lengths of the records
+/// in the tail cannot be trusted, so they are only followed while they stay in
+/// the buffer; the last partial record is counted as well.
+pub fn count_records(version: u32, mut buf: &[u8]) -> u64 {
+    let mut count = 0;
+    while !buf.is_empty() {
+        count += 1;
+        match record_len(version, buf).and_then(|len| buf.get(len..)) {
+            Some(rest) => buf = rest,
+            None => break,
+        }
+    }
+    count
+}
+
+/// Returns whether a record matching its checksum follows the damaged record
+/// at the beginning of `buf`, following the lengths in the record headers. A
+/// torn write leaves no valid record after it, while a damaged record in the
+/// middle of a segment is followed by the records written after it.
+pub fn has_valid_record_after(version: u32, buf: &[u8]) -> bool {
+    let mut buf = match record_len(version, buf).and_then(|len| buf.get(len..)) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    while !buf.is_empty() {
+        if let Frame::Complete { .. } = decode_record(version, buf) {
+            return true;
+        }
+        match record_len(version, buf).and_then(|len| buf.get(len..)) {
+            Some(rest) => buf = rest,
+            None => return false,
+        }
+    }
+    false
+}
+
+// returns the length of the record at the beginning of `buf` from its header,
+// or None if the buffer is shorter than the header.
+fn record_len(version: u32, buf: &[u8]) -> Option<usize> {
+    if version == FRAMED_TEXT_VERSION {
+        if buf.len() < FRAMED_TEXT_HEADER_LEN {
+            return None;
+        }
+        return Some(FRAMED_TEXT_HEADER_LEN + read_u32(&buf[4..]) as usize);
+    }
+
+    if buf.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let key_len = read_u32(&buf[4..]) as usize;
+    let value_len = read_u32(&buf[8..]) as usize;
+    Some(RECORD_HEADER_LEN + key_len + value_len)
+}
+
+fn decode_binary(buf: &[u8]) -> Option<Record> {
+    let key_len = read_u32(&buf[4..]) as usize;
+    let key_end = RECORD_HEADER_LEN + key_len;
+    let key = buf.get(RECORD_HEADER_LEN..key_end)?.to_vec();
+
+    match buf[12] {
+        RECORD_TYPE_SET => Some(Record::Set {
+            key,
+            value: buf[key_end..].to_vec(),
+            expires_at: None,
+        }),
+        RECORD_TYPE_SET_WITH_EXPIRY => {
+            let value_start = key_end + EXPIRY_LEN;
+            let expires_at = u64::from_le_bytes(buf.get(key_end..value_start)?.try_into().ok()?);
+            Some(Record::Set {
+                key,
+                value: buf[value_start..].to_vec(),
+                expires_at: Some(expires_at),
+            })
+        }
+        RECORD_TYPE_REMOVE => Some(Record::Remove { key }),
+        RECORD_TYPE_BLOB => Some(Record::BlobRef {
+            key,
+            blob: decode_blob_pos(&buf[key_end..])?,
+            expires_at: None,
+        }),
+        RECORD_TYPE_BLOB_WITH_EXPIRY => {
+            let value_start = key_end + EXPIRY_LEN;
+            let expires_at = u64::from_le_bytes(buf.get(key_end..value_start)?.try_into().ok()?);
+            Some(Record::BlobRef {
+                key,
+                blob: decode_blob_pos(&buf[value_start..])?,
+                expires_at: Some(expires_at),
+            })
+        }
+        RECORD_TYPE_BATCH => {
+            let mut records = Vec::new();
+            let mut rest = &buf[key_end..];
+            while !rest.is_empty() {
+                match decode_record(FORMAT_VERSION, rest) {
+                    Frame::Complete {
+                        record:
+                            record @ (Record::Set { .. }
+                            | Record::Remove { .. }
+                            | Record::BlobRef { .. }),
+                        len,
+                    } => {
+                        records.push(record);
+                        rest = &rest[len..];
+                    }
+                    _ => return None,
+                }
+            }
+            Some(Record::Batch(records))
+        }
+        _ => None,
+    }
+}
+
+fn decode_blob_pos(buf: &[u8]) -> Option<BlobPos> {
+    if buf.len() != BLOB_POS_LEN {
+        return None;
+    }
+    Some(BlobPos {
+        blob_idx: read_u32(buf),
+        offset: u64::from_le_bytes(buf[4..12].try_into().ok()?),
+        len: u64::from_le_bytes(buf[12..20].try_into().ok()?),
+        crc: read_u32(&buf[20..]),
+    })
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
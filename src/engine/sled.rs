@@ -1,6 +1,18 @@
-use super::KvsEngine;
+use super::{
+    kv::{is_expired, now_millis},
+    BatchOp, Condition, KvsEngine, ReadSet, WriteBatch,
+};
 use crate::{KvsError, Result};
-use sled::{Db, Tree};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    Db, Transactional, Tree,
+};
+use std::{ops::RangeBounds, time::Duration};
+
+// EXPIRY_TREE keeps the expiry time of the keys with a ttl, as milliseconds since
+// the unix epoch (u64, big endian). It is written in the same transaction as the
+// values, so that a value never outlives or loses its expiry.
+const EXPIRY_TREE: &str = "kvs_expiry";
 
 /// Wrapper of `sled::Db`
 #[derive(Clone)]
@@ -11,29 +23,199 @@ impl SledKvsEngine {
     pub fn new(db: Db) -> Self {
         SledKvsEngine(db)
     }
+
+    fn expiry_tree(&self) -> Result<Tree> {
+        Ok(self.0.open_tree(EXPIRY_TREE)?)
+    }
+
+    fn expires_at(&self, key: &[u8]) -> Result<Option<u64>> {
+        Ok(self.expiry_tree()?.get(key)?.map(|v| decode_expiry(&v)))
+    }
+
+    // runs `f` in a transaction over the values and their expiries, and flushes
+    // the changes afterwards.
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&TransactionalTree, &TransactionalTree) -> TxResult<T>,
+    {
+        let tree: &Tree = &self.0;
+        let expiry = self.expiry_tree()?;
+        let res = (tree, &expiry).transaction(|(tree, expiry)| f(tree, expiry))?;
+        tree.flush()?;
+        Ok(res)
+    }
 }
 
+type TxResult<T> = std::result::Result<T, ConflictableTransactionError<KvsError>>;
+
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.transaction(|tree, expiry| {
+            tree.insert(&key[..], &value[..])?;
+            expiry.remove(&key[..])?;
+            Ok(())
+        })
+    }
+
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if is_expired(self.expires_at(&key)?, now_millis()) {
+            return Ok(None);
+        }
+
         let tree: &Tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
-        tree.flush()?;
-        Ok(())
+        Ok(tree.get(key)?.map(|i_vec| i_vec.to_vec()))
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let now = now_millis();
+        self.transaction(|tree, expiry| {
+            let expires_at = expiry.remove(&key[..])?.map(|v| decode_expiry(&v));
+            match tree.remove(&key[..])? {
+                Some(_) if !is_expired(expires_at, now) => Ok(()),
+                _ => Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound)),
+            }
+        })
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.transaction(|tree, expiry| {
+            tree.insert(&key[..], &value[..])?;
+            expiry.insert(&key[..], &expires_at.to_be_bytes())?;
+            Ok(())
+        })
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        let key = key.into();
+        let now = now_millis();
+        let expires_at = self.expires_at(&key)?;
         let tree: &Tree = &self.0;
-        Ok(tree
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+        if is_expired(expires_at, now) || !tree.contains_key(&key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+
+        Ok(expires_at.map(|expires_at| Duration::from_millis(expires_at - now)))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let now = now_millis();
+        self.transaction(|tree, expiry| {
+            let expires_at = expiry.get(&key[..])?.map(|v| decode_expiry(&v));
+            if is_expired(expires_at, now) || tree.get(&key[..])?.is_none() {
+                return Err(ConflictableTransactionError::Abort(KvsError::KeyNotFound));
+            }
+            expiry.remove(&key[..])?;
+            Ok(())
+        })
+    }
+
+    fn set_if(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        condition: Condition,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let now = now_millis();
+        self.transaction(|tree, expiry| {
+            let expires_at = expiry.get(&key[..])?.map(|v| decode_expiry(&v));
+            let current = tree.get(&key[..])?.filter(|_| !is_expired(expires_at, now));
+            if !condition.holds(current.as_deref()) {
+                return Err(ConflictableTransactionError::Abort(
+                    KvsError::ConditionFailed,
+                ));
+            }
+
+            // like `set`, a conditional write drops the expiry of the key.
+            tree.insert(&key[..], &value[..])?;
+            expiry.remove(&key[..])?;
+            Ok(())
+        })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.transaction(|tree, expiry| apply_batch(tree, expiry, &batch))
+    }
+
+    fn commit_transaction(&self, reads: ReadSet, batch: WriteBatch) -> Result<()> {
+        let now = now_millis();
+        self.transaction(|tree, expiry| {
+            for (key, read) in &reads {
+                let expires_at = expiry.get(&key[..])?.map(|v| decode_expiry(&v));
+                let value = tree.get(&key[..])?.filter(|_| !is_expired(expires_at, now));
+                if value.as_deref() != read.as_deref() {
+                    return Err(ConflictableTransactionError::Abort(
+                        KvsError::TransactionConflict,
+                    ));
+                }
+            }
+            apply_batch(tree, expiry, &batch)
+        })
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let now = now_millis();
+        let expiry = self.expiry_tree()?;
         let tree: &Tree = &self.0;
-        tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        tree.flush()?;
-        Ok(())
+
+        let mut pairs = Vec::new();
+        for pair in tree.range(range) {
+            if pairs.len() == limit {
+                break;
+            }
+            let (key, value) = pair?;
+            if is_expired(expiry.get(&key)?.map(|v| decode_expiry(&v)), now) {
+                continue;
+            }
+            pairs.push((key.to_vec(), value.to_vec()));
+        }
+
+        Ok(pairs)
     }
 }
+
+fn apply_batch(
+    tree: &TransactionalTree,
+    expiry: &TransactionalTree,
+    batch: &WriteBatch,
+) -> TxResult<()> {
+    for op in batch.ops() {
+        match op {
+            BatchOp::Put { key, value } => {
+                tree.insert(&key[..], &value[..])?;
+            }
+            BatchOp::Delete { key } => {
+                tree.remove(&key[..])?;
+            }
+        }
+        expiry.remove(op_key(op))?;
+    }
+    Ok(())
+}
+
+fn op_key(op: &BatchOp) -> &[u8] {
+    match op {
+        BatchOp::Put { key, .. } | BatchOp::Delete { key } => key,
+    }
+}
+
+fn decode_expiry(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_be_bytes(bytes)
+}
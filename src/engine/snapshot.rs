@@ -0,0 +1,238 @@
+use super::{
+    blob, hint,
+    keydir::Version,
+    kv::{is_expired, remove_if_exists, CommandPos},
+    reader::KvsReader,
+    record::Record,
+};
+use crate::{KvsError, Result};
+use log::{error, info};
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::RangeBounds,
+    sync::{Arc, Mutex},
+};
+
+/// Snapshot is a point-in-time view of a KvStore, created by
+/// `KvStore::snapshot`. The writes and the compactions after its creation are
+/// not visible through it, so the keys read through a snapshot are always
+/// consistent with each other.
+///
+/// A snapshot pins the version of the key_dir it is created at, so the key_dir
+/// keeps the positions superseded by the later writes and compactions, which
+/// costs memory proportional to the keys changed while the snapshot is alive.
+/// It keeps the log files live at its creation on the disk until it is
+/// dropped. The blob files collected while a snapshot is alive are kept as
+/// well.
+pub struct Snapshot {
+    version: Version,
+    // now is the time of the creation; the keys expired by then are not in
+    // the snapshot.
+    now: u64,
+    reader: KvsReader,
+    _pin: SegmentPin,
+}
+
+impl Snapshot {
+    pub(super) fn new(
+        version: Version,
+        segments: &[u32],
+        now: u64,
+        reader: KvsReader,
+        pins: Arc<Mutex<SegmentPins>>,
+    ) -> Snapshot {
+        let segments: BTreeSet<u32> = segments.iter().copied().collect();
+        pins.lock().unwrap().pin(&segments);
+
+        Snapshot {
+            _pin: SegmentPin {
+                pins,
+                segments,
+                reader: reader.clone(),
+            },
+            version,
+            now,
+            reader,
+        }
+    }
+
+    pub fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        match self.version.get(&key) {
+            Some(cmd_pos) if !is_expired(cmd_pos.expires_at, self.now) => {
+                self.read_value(&key, &cmd_pos).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the value of the key as a string. It fails with
+    /// `KvsError::Utf8` if the value is not valid UTF-8.
+    pub fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Returns the key/value pairs whose keys are in `range` in key order, up
+    /// to `limit` pairs.
+    pub fn scan_bytes<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.positions(range)
+            .take(limit)
+            .map(|(key, cmd_pos)| {
+                let value = self.read_value(&key, &cmd_pos)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    /// Iterates over every key/value pair of the snapshot in key order.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_ {
+        self.positions(..).map(|(key, cmd_pos)| {
+            let value = self.read_value(&key, &cmd_pos)?;
+            Ok((key, value))
+        })
+    }
+
+    /// Returns the number of keys in the snapshot. The keys are counted by
+    /// walking over them, without reading their values.
+    pub fn len(&self) -> usize {
+        self.positions(..).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions(..).next().is_none()
+    }
+
+    // returns the keys of the snapshot in `range` along with their positions.
+    fn positions<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = (Vec<u8>, CommandPos)> + '_ {
+        self.version
+            .range(range)
+            .filter(|(_, cmd_pos)| !is_expired(cmd_pos.expires_at, self.now))
+    }
+
+    fn read_value(&self, key: &[u8], cmd_pos: &CommandPos) -> Result<Vec<u8>> {
+        match self.reader.read_record(cmd_pos)? {
+            Record::Set { value, .. } => Ok(value),
+            _ => Err(KvsError::UnexpectedCommandType(
+                String::from_utf8_lossy(key).into_owned(),
+            )),
+        }
+    }
+}
+
+/// SegmentPins counts the snapshots referring to each log file. The compaction
+/// leaves the log files pinned by a snapshot on the disk, and they are removed
+/// once the last snapshot pinning them is dropped.
+///
+/// The blob files a snapshot refers to are not known without reading the
+/// records, so the blob files collected by the blob GC are kept until every
+/// snapshot is dropped.
+///
+/// The log files left behind by a crash are not in the MANIFEST anymore, so they
+/// are removed once the store is opened again.
+#[derive(Debug, Default)]
+pub struct SegmentPins {
+    counts: HashMap<u32, usize>,
+    // obsolete keeps the pinned log files that are replaced by a compaction.
+    obsolete: BTreeSet<u32>,
+    snapshots: usize,
+    // obsolete_blobs keeps the blob files collected while a snapshot is alive.
+    obsolete_blobs: BTreeSet<u32>,
+}
+
+impl SegmentPins {
+    fn pin(&mut self, segments: &BTreeSet<u32>) {
+        self.snapshots += 1;
+        for idx in segments {
+            *self.counts.entry(*idx).or_insert(0) += 1;
+        }
+    }
+
+    // unpins the log files, and returns the obsolete log and blob files that
+    // are not pinned anymore.
+    fn unpin(&mut self, segments: &BTreeSet<u32>) -> (Vec<u32>, BTreeSet<u32>) {
+        self.snapshots -= 1;
+        let blobs = if self.snapshots == 0 {
+            std::mem::take(&mut self.obsolete_blobs)
+        } else {
+            BTreeSet::new()
+        };
+
+        let mut removable = Vec::new();
+        for idx in segments {
+            if let Some(count) = self.counts.get_mut(idx) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(idx);
+                    if self.obsolete.remove(idx) {
+                        removable.push(*idx);
+                    }
+                }
+            }
+        }
+        (removable, blobs)
+    }
+
+    /// Returns whether the log file replaced by a compaction can be removed
+    /// now. Otherwise, it is removed once the snapshots pinning it are dropped.
+    pub fn release(&mut self, log_idx: u32) -> bool {
+        if self.counts.contains_key(&log_idx) {
+            self.obsolete.insert(log_idx);
+            return false;
+        }
+        true
+    }
+
+    /// Returns whether the blob file collected by the blob GC can be removed
+    /// now. Otherwise, it is removed once every snapshot is dropped.
+    pub fn release_blob(&mut self, blob_idx: u32) -> bool {
+        if self.snapshots > 0 {
+            self.obsolete_blobs.insert(blob_idx);
+            return false;
+        }
+        true
+    }
+}
+
+// SegmentPin is the pin of a snapshot on the log files it refers to.
+struct SegmentPin {
+    pins: Arc<Mutex<SegmentPins>>,
+    segments: BTreeSet<u32>,
+    reader: KvsReader,
+}
+
+impl Drop for SegmentPin {
+    fn drop(&mut self) {
+        let (removable, blobs) = self.pins.lock().unwrap().unpin(&self.segments);
+        for idx in removable {
+            info!(
+                "[snapshot]: removing log file {} released by the last snapshot",
+                idx
+            );
+            self.reader.release(idx);
+            let path = &self.reader.path;
+            let removed = remove_if_exists(&path.join(format!("{}.log", idx)))
+                .and_then(|_| remove_if_exists(&hint::hint_path(path, idx)));
+            if let Err(e) = removed {
+                error!("[snapshot]: failed to remove log file {}, err: {}", idx, e);
+            }
+        }
+        for idx in blobs {
+            info!(
+                "[snapshot]: removing blob file {} released by the last snapshot",
+                idx
+            );
+            self.reader.release_blob(idx);
+            if let Err(e) = remove_if_exists(&blob::blob_path(&self.reader.path, idx)) {
+                error!("[snapshot]: failed to remove blob file {}, err: {}", idx, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use super::{KvsEngine, WriteBatch};
+use crate::Result;
+
+use std::collections::BTreeMap;
+
+/// ReadSet maps the keys read by a transaction to the values they had, or
+/// `None` if they did not exist.
+pub type ReadSet = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Transaction is an optimistic read-modify-write transaction over multiple
+/// keys, created by `KvsEngine::transaction`.
+///
+/// Nothing is locked while the transaction runs. Its writes are buffered, and
+/// `commit` applies them atomically only if none of the keys it has read are
+/// changed in the meantime; otherwise the commit fails with
+/// `KvsError::TransactionConflict` and the transaction can be retried from the
+/// start. Dropping a transaction without committing it aborts it.
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    reads: ReadSet,
+    // writes keeps the latest write of each key in the transaction, `None` for
+    // the removed ones, so that the transaction reads its own writes.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    batch: WriteBatch,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(super) fn new(engine: E) -> Transaction<E> {
+        Transaction {
+            engine,
+            reads: ReadSet::new(),
+            writes: BTreeMap::new(),
+            batch: WriteBatch::new(),
+        }
+    }
+
+    /// Reads the value of the key, adding the key to the read set. Reading a
+    /// key again returns the same value, unless the transaction writes it.
+    pub fn get_bytes(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+
+        let value = self.engine.get_bytes(key.clone())?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Same as `get_bytes`, for the values known to be UTF-8.
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        let (key, value) = (key.into(), value.into());
+        self.writes.insert(key.clone(), Some(value.clone()));
+        self.batch.put(key, value);
+    }
+
+    /// Removes the key; like in a `WriteBatch`, removing a key that does not
+    /// exist is not an error.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        let key = key.into();
+        self.writes.insert(key.clone(), None);
+        self.batch.delete(key);
+    }
+
+    /// Validates the read set and applies the writes atomically.
+    pub fn commit(self) -> Result<()> {
+        self.engine.commit_transaction(self.reads, self.batch)
+    }
+}
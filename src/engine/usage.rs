@@ -0,0 +1,78 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// SegmentUsage keeps how many bytes of records are written into each log file,
+/// and how many of them are dead: overwritten or removed values, tombstones and
+/// batch headers. The compaction picks the log files by their ratio of dead
+/// bytes, so the logs holding mostly live data are not rewritten.
+#[derive(Debug, Default)]
+pub struct SegmentUsage {
+    segments: BTreeMap<u32, Usage>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Usage {
+    total: u64,
+    dead: u64,
+}
+
+impl Usage {
+    fn garbage_ratio(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.dead as f64 / self.total as f64
+    }
+}
+
+impl SegmentUsage {
+    /// Records `len` bytes written into the log file.
+    pub fn written(&mut self, log_idx: u32, len: u64) {
+        self.segments.entry(log_idx).or_default().total += len;
+    }
+
+    /// Records `len` bytes of the log file that are not needed anymore.
+    pub fn dead(&mut self, log_idx: u32, len: u64) {
+        let usage = self.segments.entry(log_idx).or_default();
+        usage.dead = (usage.dead + len).min(usage.total);
+    }
+
+    /// Returns the number of dead bytes that a compaction would reclaim, which
+    /// are the dead bytes of the log files whose garbage ratio is at least
+    /// `min_ratio`.
+    pub fn reclaimable(&self, min_ratio: f64) -> u64 {
+        self.segments
+            .values()
+            .filter(|usage| usage.garbage_ratio() >= min_ratio)
+            .map(|usage| usage.dead)
+            .sum()
+    }
+
+    /// Returns up to `max` log files whose garbage ratio is at least
+    /// `min_ratio`, the highest ratios first.
+    pub fn pick(&self, min_ratio: f64, max: usize) -> Vec<u32> {
+        let mut candidates: Vec<(u32, f64)> = self
+            .segments
+            .iter()
+            .map(|(idx, usage)| (*idx, usage.garbage_ratio()))
+            .filter(|(_, ratio)| *ratio >= min_ratio)
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        candidates
+            .into_iter()
+            .take(max)
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Returns whether a log file older than `log_idx` is left out of `segments`.
+    pub fn has_older(&self, log_idx: u32, segments: &BTreeSet<u32>) -> bool {
+        self.segments
+            .range(..log_idx)
+            .any(|(idx, _)| !segments.contains(idx))
+    }
+
+    /// Forgets a log file that is replaced by a compaction.
+    pub fn retire(&mut self, log_idx: u32) {
+        self.segments.remove(&log_idx);
+    }
+}
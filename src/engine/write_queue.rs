@@ -0,0 +1,130 @@
+use crate::{KvsError, Result};
+
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Condvar, Mutex},
+};
+
+// WriteQueue implements group commit for concurrent writers.
+//
+// Every writer queues its item and waits. The first writer that finds no
+// leader becomes the leader: it takes every item queued so far, commits them as
+// one batch, hands the result of each item to its writer, and gives up the
+// leadership. The items queued while a batch is being committed form the next
+// batch. So, under contention, a single write and sync is shared by many writers.
+pub struct WriteQueue<T> {
+    state: Mutex<State<T>>,
+    done: Condvar,
+}
+
+struct State<T> {
+    // pending keeps the items waiting for the next batch along with their tickets.
+    pending: Vec<(u64, T)>,
+    // results keeps the results of the committed items until their writers
+    // pick them up.
+    results: HashMap<u64, Result<()>>,
+    next_ticket: u64,
+    has_leader: bool,
+}
+
+impl<T> WriteQueue<T> {
+    pub fn new() -> WriteQueue<T> {
+        WriteQueue {
+            state: Mutex::new(State {
+                pending: Vec::new(),
+                results: HashMap::new(),
+                next_ticket: 0,
+                has_leader: false,
+            }),
+            done: Condvar::new(),
+        }
+    }
+
+    /// Queues `item` and blocks until it is committed, either by this writer or
+    /// by another writer leading the batch that includes it.
+    ///
+    /// `commit` is called by the leader with the items of a batch in the order
+    /// they were queued, and must return one result per item in the same order.
+    pub fn write<F>(&self, item: T, commit: F) -> Result<()>
+    where
+        F: FnOnce(Vec<T>) -> Vec<Result<()>>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, item));
+
+        loop {
+            if let Some(result) = state.results.remove(&ticket) {
+                return result;
+            }
+            if !state.has_leader {
+                break;
+            }
+            state = self.done.wait(state).unwrap();
+        }
+
+        // become the leader; the own item of the leader is always in the batch,
+        // since nobody else can take the pending items without the leadership.
+        state.has_leader = true;
+        let (tickets, items): (Vec<u64>, Vec<T>) =
+            mem::take(&mut state.pending).into_iter().unzip();
+        drop(state);
+
+        let mut leader = Leader {
+            queue: self,
+            ticket,
+            tickets,
+        };
+        let results = commit(items);
+        debug_assert_eq!(leader.tickets.len(), results.len());
+
+        let mut state = self.state.lock().unwrap();
+        let tickets = mem::take(&mut leader.tickets);
+        state.results.extend(tickets.into_iter().zip(results));
+        state.has_leader = false;
+        self.done.notify_all();
+
+        state
+            .results
+            .remove(&ticket)
+            .expect("the batch of the leader includes its own item")
+    }
+}
+
+// Leader gives up the leadership if the commit of the batch panics, failing the
+// items of the other writers of the batch, so that they and the later writers do
+// not wait for a leader forever. The own item of the leader gets no result, since
+// the panic is propagated to its writer.
+struct Leader<'a, T> {
+    queue: &'a WriteQueue<T>,
+    // ticket is the ticket of the own item of the leader.
+    ticket: u64,
+    // tickets is the tickets of the batch, until their results are handed out.
+    tickets: Vec<u64>,
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        if self.tickets.is_empty() {
+            return;
+        }
+
+        let mut state = self
+            .queue
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for ticket in self.tickets.drain(..).filter(|t| *t != self.ticket) {
+            state.results.insert(
+                ticket,
+                Err(KvsError::IO(
+                    "commit of the write batch panicked".to_string(),
+                )),
+            );
+        }
+        state.has_leader = false;
+        self.queue.done.notify_all();
+    }
+}
@@ -1,6 +1,7 @@
 use std::{io, string::FromUtf8Error};
 
 use failure::Fail;
+use sled::transaction::TransactionError;
 
 #[derive(Fail, Debug)]
 pub enum KvsError {
@@ -35,6 +36,34 @@ pub enum KvsError {
     /// Key or value is invalid UTF-8 sequence
     #[fail(display = "Unexpected  {}", _0)]
     UnexpectedCommandType(String),
+
+    /// Record in the log does not match its checksum
+    #[fail(display = "corrupted record in log {} at offset {}", _0, _1)]
+    Corruption(u32, u64),
+
+    /// Value in a blob file does not match its checksum
+    #[fail(display = "corrupted value in blob file {} at offset {}", _0, _1)]
+    BlobCorruption(u32, u64),
+
+    /// Condition of a conditional write does not hold
+    #[fail(display = "Condition failed")]
+    ConditionFailed,
+
+    /// Key read by a transaction is changed before the transaction commits
+    #[fail(display = "Transaction conflict")]
+    TransactionConflict,
+
+    /// Write to a store opened in read-only mode
+    #[fail(display = "Store is opened in read-only mode")]
+    ReadOnly,
+
+    /// Option of the store is out of its valid range
+    #[fail(display = "Invalid option: {}", _0)]
+    InvalidOption(String),
+
+    /// Log file is written in a format version that is not known
+    #[fail(display = "unsupported log format version {}", _0)]
+    UnsupportedFormat(u32),
 }
 
 impl From<serde_json::Error> for KvsError {
@@ -61,6 +90,15 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<TransactionError<KvsError>> for KvsError {
+    fn from(err: TransactionError<KvsError>) -> KvsError {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => KvsError::Sled(err),
+        }
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
@@ -1,10 +1,12 @@
-mod buf_reader;
 mod buf_writer;
 mod data_format;
 mod engine;
 mod error;
 pub mod server;
 pub mod thread_pool;
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
+pub use engine::{
+    BatchOp, CacheStats, CachedEngine, Condition, Durability, KvStore, KvStoreOptions, KvsEngine,
+    ReadSet, SledKvsEngine, Snapshot, TornWrite, Transaction, WriteBatch, DEFAULT_MAX_SEGMENT_SIZE,
+};
 pub use error::{KvsError, Result};
 pub mod transport;
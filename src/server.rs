@@ -1,183 +1,33 @@
 use std::{
-    cell::RefCell,
-    collections::BTreeMap,
-    env::current_dir,
-    fs::{self, File, OpenOptions},
-    io::{self, BufRead, BufReader, BufWriter, Write},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
     net::TcpListener,
-    path::PathBuf,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
-    thread::{self, JoinHandle},
+    time::Duration,
 };
 
-use crossbeam_channel::{unbounded, Receiver};
 use log::{debug, error, info};
 
 use crate::{
-    buf_writer::BufWriterWithPos,
-    engine::{CommandPos, KvsEngine, KvsReader},
+    engine::KvsEngine,
     thread_pool::ThreadPool,
-    transport::Response,
-    KvStore, Result,
+    transport::{self, Command, Response, KEY_NOT_FOUND},
+    KvsError, Result,
 };
 use kvs_protocol::{deserializer::deserialize, request::Request};
 
-pub struct KvServer {
-    pub engine: KvStore,
-    rx_compaction: Receiver<TxMessage>,
-    path: PathBuf,
+/// KvServer serves the requests of kvs-client through the given storage engine.
+pub struct KvServer<E: KvsEngine> {
+    pub engine: E,
 }
 
-pub struct TxMessage {
-    pub log_idx: Arc<AtomicU64>,
-    pub path: PathBuf,
-}
-
-impl KvServer {
-    pub fn new_with_path(p: PathBuf) -> KvServer {
-        let (tx_compaction, rx_compaction) = unbounded::<TxMessage>();
-
-        let engine = KvStore::new(tx_compaction.clone(), p.clone()).unwrap();
-
-        KvServer {
-            engine: engine.to_owned(),
-            rx_compaction,
-            path: p,
-        }
-    }
-
-    pub fn new() -> KvServer {
-        let (tx_compaction, rx_compaction) = unbounded::<TxMessage>();
-
-        let p = current_dir().unwrap();
-        let engine = KvStore::new(tx_compaction.clone(), p.clone()).unwrap();
-
-        KvServer {
-            engine: engine.to_owned(),
-            rx_compaction,
-            path: p,
-        }
+impl<E: KvsEngine> KvServer<E> {
+    /// Creates a `KvServer` on top of an already opened engine.
+    pub fn new(engine: E) -> KvServer<E> {
+        KvServer { engine }
     }
 
     pub fn start<P: ThreadPool>(&self, addr: String, thread_pool: P) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
 
-        let rx_compaction = self.rx_compaction.to_owned();
-        let log_writer = Arc::clone(&self.engine.log_writer);
-        let key_dir = self.engine.key_dir.clone();
-        let uncompacted = Arc::clone(&self.engine.uncompacted);
-
-        let reader = KvsReader {
-            path: self.path.clone(),
-            readers: RefCell::new(BTreeMap::new()),
-        };
-
-        let r: JoinHandle<Result<()>> = thread::spawn(move || loop {
-            println!("[receiver]: waiting for a signal");
-            let msg = rx_compaction.recv().unwrap();
-            {
-                let mut log_writer = log_writer.lock().unwrap();
-                let mut log_idx = msg.log_idx.load(Ordering::SeqCst);
-                let path = msg.path;
-
-                let new_compaction_log_idx = log_idx + 1;
-                let new_compaction_file_path =
-                    path.join(format!("{}.log", &new_compaction_log_idx));
-
-                println!(
-                    "[compaction]: new compaction log file idx {}, compaction file name {:?}",
-                    new_compaction_log_idx, new_compaction_file_path
-                );
-
-                // create a writer for the log entry which will include the command details of the
-                // existing commands on the memory.
-                let mut compaction_log_writer: BufWriterWithPos<File> = BufWriterWithPos::new(
-                    OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .open(&new_compaction_file_path)?,
-                )?;
-
-                let mut new_starting_pos = 0 as u64;
-
-                // iterate through the active keys on the memory.
-                for mut entry in key_dir.iter_mut() {
-                    let copied_bytes = reader
-                        .read_cmd_from_log_and_copy(entry.value(), &mut compaction_log_writer)?;
-
-                    let v = entry.value_mut();
-                    *v = CommandPos {
-                        log_idx: new_compaction_log_idx as u32,
-                        starting_pos: new_starting_pos,
-                        len: copied_bytes,
-                    };
-
-                    new_starting_pos += copied_bytes;
-                }
-                compaction_log_writer.flush()?;
-
-                let keys_to_delete: Vec<u32> = {
-                    let borrowed_map = reader.readers.borrow();
-                    borrowed_map
-                        .iter()
-                        .filter_map(|(&key, reader)| {
-                            // Your condition for deletion goes here
-                            // For example, let's say we want to delete readers at position 0
-                            if key < new_compaction_log_idx as u32 {
-                                Some(key)
-                            } else {
-                                None
-                            }
-                        })
-                        .collect()
-                };
-
-                {
-                    let mut borrowed_map = reader.readers.borrow_mut();
-                    for key in keys_to_delete {
-                        borrowed_map.remove(&key);
-                        println!("Removed reader with key: {}", key);
-                    }
-                }
-
-                // todo: this is not efficient in case of big number of log files.
-                // it always starts iterating from 1 to the recent log file and tries to delete them all the time.
-                for i in 1..new_compaction_log_idx as u32 {
-                    fs::remove_file(path.join(format!("{}.log", i))).or_else(|e| {
-                        if e.kind() == io::ErrorKind::NotFound {
-                            Ok(())
-                        } else {
-                            Err(e)
-                        }
-                    })?;
-                }
-
-                // self.log_idx + 1 corresponds to the new log file which will include all active
-                // commands in the memory. So, the new requests need to be moved to self.log_idx + 2
-                // which will be new log entry in the file system.
-                log_idx += 2;
-                // now, update the writer so that the new log entries will be written into a new log file.
-                *log_writer = BufWriterWithPos::new(
-                    OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .open(path.join(format!("{}.log", log_idx)))?,
-                )?;
-
-                msg.log_idx.store(log_idx, Ordering::SeqCst);
-                {
-                    match uncompacted.try_write() {
-                        Ok(mut u) => *u = 0,
-                        Err(_) => info!("failed to obtain a lock while updating the uncompaction"),
-                    }
-                }
-                info!("[compaction]: writer of the compaction is updated! the new commands will be appended into the log idx: {}", log_idx);
-            }
-        });
-
         for stream in listener.incoming() {
             let engine = self.engine.clone();
             thread_pool.spawn(move || match stream {
@@ -201,6 +51,18 @@ where
     let mut request_reader = BufReader::new(stream.try_clone().unwrap());
     let mut response_writer = BufWriter::new(stream);
 
+    // the commands are sent as frames, which are told apart from the requests
+    // of kvs_protocol by their first byte.
+    if request_reader.fill_buf()?.first() == Some(&transport::FRAME_MARKER) {
+        return match transport::read_command(&mut request_reader)? {
+            Some(Command::Multi) => {
+                handle_transaction(engine, &mut request_reader, &mut response_writer)
+            }
+            Some(cmd) => handle_command(engine, cmd, &mut response_writer),
+            None => Ok(()),
+        };
+    }
+
     // TODO: error handling in the read_line
     let mut buf = String::new();
     if let Err(err) = request_reader.read_line(&mut buf) {
@@ -223,7 +85,7 @@ where
                         if let Some(val) = v {
                             resp.result = val.clone();
                         } else {
-                            resp.error = Some("Key not found".to_string());
+                            resp.error = Some(KEY_NOT_FOUND.to_string());
                         }
                         info!("==> DONE GET request {} -> {:?}", key, resp);
 
@@ -254,7 +116,7 @@ where
                     if let Err(e) = engine.remove(key.to_string()) {
                         error!("failed to remove the key, err: {}", e);
 
-                        resp.error = Some("Key not found".to_string());
+                        resp.error = Some(KEY_NOT_FOUND.to_string());
                     }
 
                     info!("==> DONE RM request {} ", key);
@@ -267,78 +129,174 @@ where
     }
 }
 
-// // compaction runs merging of bitcask.
-// // when uncompacted bytes amount reaches the threshold, the compaction will be run in next set command.
-// //
-// // 1- it first creates a new log entry which will copy entries from previous logs that are
-// // active at the moment (which means active in key_dir hash map). Therefore, the new log entry
-// // will be the reflection of our in-memory key_dir map.
-// // 2- after creating this new log file, it removes the previous log files.
-// fn compaction(
-//     writer: &mut BufWriterWithPos<File>,
-//     log_idx: u64,
-//     path: PathBuf,
-//     key_dir: DashMap<String, CommandPos>,
-//     reader: KvsReader,
-// ) -> Result<()> {
-//     // Create a new log file including all the commands stored in in-memory keydir map.
-//     // So, it will be our new starting idx for logs.
-//     // All logs up to `self.log_idx + 1` will be included in to `new_log_file_path`.
-//     let new_compaction_log_idx = log_idx + 1;
-//     let new_compaction_file_path = path.join(format!("{}.log", &new_compaction_log_idx));
-
-//     println!(
-//         "[compaction]: new compaction log file idx {}, compaction file name {:?}",
-//         new_compaction_log_idx, new_compaction_file_path
-//     );
-
-//     // create a writer for the log entry which will include the command details of the
-//     // existing commands on the memory.
-//     let mut compaction_log_writer: BufWriterWithPos<File> = BufWriterWithPos::new(
-//         OpenOptions::new()
-//             .create(true)
-//             .write(true)
-//             .open(&new_compaction_file_path)?,
-//     )?;
-
-//     let mut new_starting_pos = 0 as u64;
-//     info!("=====> COPYING OLD LOGS");
-//     // iterate through the active keys on the memory.
-//     for mut entry in key_dir.iter_mut() {
-//         let copied_bytes = self
-//             .reader
-//             .read_cmd_from_log_and_copy(entry.value(), &mut compaction_log_writer)?;
-
-//         let v = entry.value_mut();
-//         *v = CommandPos {
-//             log_idx: new_compaction_log_idx as u32,
-//             starting_pos: new_starting_pos,
-//             len: copied_bytes,
-//         };
+// handles the requests sent as a Command.
+fn handle_command<E, W>(engine: E, cmd: Command, response_writer: &mut W) -> Result<()>
+where
+    E: KvsEngine,
+    W: Write,
+{
+    let mut resp: Response = Response {
+        ..Default::default()
+    };
+
+    match cmd {
+        Command::Get { key } => {
+            info!("==> GET request {}", String::from_utf8_lossy(&key));
+            match engine.get_bytes(key) {
+                Ok(Some(value)) => resp.value = Some(value),
+                Ok(None) => resp.error = Some(KEY_NOT_FOUND.to_string()),
+                Err(e) => {
+                    error!("failed to read the key, err: {}", e);
+                    resp.error = Some(e.to_string());
+                }
+            }
+        }
+        Command::Set { key, value } => {
+            info!("==> SET request {}", String::from_utf8_lossy(&key));
+            if let Err(e) = engine.set(key, value) {
+                error!("failed to write the key, err: {}", e);
+                resp.error = Some(e.to_string());
+            }
+        }
+        Command::Remove { key } => {
+            info!("==> RM request {}", String::from_utf8_lossy(&key));
+            match engine.remove(key) {
+                Ok(()) => {}
+                Err(KvsError::KeyNotFound) => resp.error = Some(KEY_NOT_FOUND.to_string()),
+                Err(e) => {
+                    error!("failed to remove the key, err: {}", e);
+                    resp.error = Some(e.to_string());
+                }
+            }
+        }
+        Command::Batch(batch) => {
+            info!("==> BATCH request with {} writes", batch.len());
+            if let Err(e) = engine.write_batch(batch) {
+                error!("failed to write the batch, err: {}", e);
+                resp.error = Some(e.to_string());
+            }
+            info!("==> DONE BATCH request");
+        }
+        Command::SetWithTtl { key, value, ttl_ms } => {
+            info!(
+                "==> SET request {} with ttl {}ms",
+                String::from_utf8_lossy(&key),
+                ttl_ms
+            );
+            if let Err(e) = engine.set_with_ttl(key, value, Duration::from_millis(ttl_ms)) {
+                error!("failed to write the key, err: {}", e);
+                resp.error = Some(e.to_string());
+            }
+        }
+        Command::Ttl { key } => {
+            info!("==> TTL request {}", String::from_utf8_lossy(&key));
+            match engine.ttl(key) {
+                Ok(Some(ttl)) => resp.result = ttl.as_millis().to_string(),
+                Ok(None) => resp.result = "-1".to_string(),
+                Err(KvsError::KeyNotFound) => resp.error = Some(KEY_NOT_FOUND.to_string()),
+                Err(e) => resp.error = Some(e.to_string()),
+            }
+        }
+        Command::SetIf {
+            key,
+            value,
+            condition,
+        } => {
+            info!(
+                "==> SET request {} if {:?}",
+                String::from_utf8_lossy(&key),
+                condition
+            );
+            if let Err(e) = engine.set_if(key, value, condition) {
+                error!("failed to write the key, err: {}", e);
+                resp.error = Some(e.to_string());
+            }
+        }
+        Command::Persist { key } => {
+            info!("==> PERSIST request {}", String::from_utf8_lossy(&key));
+            match engine.persist(key) {
+                Ok(()) => {}
+                Err(KvsError::KeyNotFound) => resp.error = Some(KEY_NOT_FOUND.to_string()),
+                Err(e) => {
+                    error!("failed to persist the key, err: {}", e);
+                    resp.error = Some(e.to_string());
+                }
+            }
+        }
+        // Multi is handled by handle_transaction.
+        Command::Multi | Command::Exec | Command::Discard => {
+            resp.error = Some("no transaction in progress".to_string());
+        }
+    }
 
-//         new_starting_pos += copied_bytes;
-//     }
-//     compaction_log_writer.flush()?;
-//     info!("=====> COPYING OLD LOGS DONE");
+    write_response(response_writer, &resp)
+}
 
-//     // todo: this is not efficient in case of big number of log files.
-//     // it always starts iterating from 1 to the recent log file and tries to delete them all the time.
-//     for i in 1..new_compaction_log_idx {
-//         fs::remove_file(path.join(format!("{}.log", i)))?;
-//     }
+// handles the commands of a transaction begun by `Command::Multi`, until the
+// transaction is ended or the connection is closed.
+fn handle_transaction<E, R, W>(
+    engine: E,
+    request_reader: &mut R,
+    response_writer: &mut W,
+) -> Result<()>
+where
+    E: KvsEngine,
+    R: Read,
+    W: Write,
+{
+    info!("==> MULTI request");
+    let mut txn = engine.transaction();
+    write_response(response_writer, &Response::default())?;
+
+    loop {
+        let cmd = match transport::read_command(request_reader) {
+            Ok(None) => {
+                info!("==> connection closed, transaction discarded");
+                return Ok(());
+            }
+            // a frame that cannot be decoded is answered with the error; the
+            // reader is at the next frame already.
+            Err(KvsError::Parser(e)) => Err(e),
+            Err(e) => return Err(e),
+            Ok(Some(cmd)) => Ok(cmd),
+        };
 
-//     // self.log_idx + 1 corresponds to the new log file which will include all active
-//     // commands in the memory. So, the new requests need to be moved to self.log_idx + 2
-//     // which will be new log entry in the file system.
-//     log_idx += 2;
-//     // now, update the writer so that the new log entries will be written into a new log file.
-//     *writer = BufWriterWithPos::new(
-//         OpenOptions::new()
-//             .create(true)
-//             .write(true)
-//             .open(path.join(format!("{}.log", log_idx)))?,
-//     )?;
-//     info!("[compaction]: writer of the compaction is updated! the new commands will be appended into the log idx: {}", log_idx);
+        let mut resp: Response = Response {
+            ..Default::default()
+        };
+        match cmd {
+            Ok(Command::Get { key }) => match txn.get_bytes(key) {
+                Ok(Some(value)) => resp.value = Some(value),
+                Ok(None) => resp.error = Some(KEY_NOT_FOUND.to_string()),
+                Err(e) => {
+                    error!("failed to read the key, err: {}", e);
+                    resp.error = Some(e.to_string());
+                }
+            },
+            Ok(Command::Set { key, value }) => txn.set(key, value),
+            Ok(Command::Remove { key }) => txn.remove(key),
+            Ok(Command::Exec) => {
+                info!("==> EXEC request");
+                if let Err(e) = txn.commit() {
+                    error!("failed to commit the transaction, err: {}", e);
+                    resp.error = Some(e.to_string());
+                }
+                return write_response(response_writer, &resp);
+            }
+            Ok(Command::Discard) => {
+                info!("==> DISCARD request");
+                return write_response(response_writer, &resp);
+            }
+            Ok(_) => resp.error = Some("command is not allowed in a transaction".to_string()),
+            Err(e) => {
+                error!("failed to deserialize the request, err: {}", e);
+                resp.error = Some(e.to_string());
+            }
+        }
+        write_response(response_writer, &resp)?;
+    }
+}
 
-//     Ok(())
-// }
+fn write_response<W: Write>(response_writer: &mut W, resp: &Response) -> Result<()> {
+    transport::write_response(response_writer, resp)
+}
@@ -1,5 +1,9 @@
+use std::io::{Read, Write};
+
 use serde::{Deserialize, Serialize};
 
+use crate::{BatchOp, Condition, KvsError, Result, WriteBatch};
+
 // #[derive(Serialize, Deserialize, Debug)]
 // pub enum Request {
 //     Get { key: String },
@@ -7,10 +11,371 @@ use serde::{Deserialize, Serialize};
 //     Rm { key: String },
 // }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+// Wire format of the commands sent by kvs-client and their responses.
+//
+// Each command and each response is sent as a frame laid out as:
+//
+// | FRAME_MARKER (u8) | payload length (u32) | payload |
+//
+// where all integers are little endian. The marker sets a frame apart from the
+// requests of kvs_protocol, which are JSON lines starting with `{`, so the
+// server tells them apart by the first byte of a connection.
+//
+// The payload of a command is its type (u8) followed by its fields in the order
+// they are declared. The keys and values are laid out as
+// | length (u32) | bytes |, so they are carried as they are, including newlines
+// and NULs. A condition is its type (u8) followed by the expected value of
+// `Condition::Equals`, and a batch is the number of its writes (u32) followed by
+// the writes, each of them a type (u8), the key and the value of a put.
+//
+// The payload of a response is its error, its result and its value. The error
+// and the value are prefixed with a byte telling whether they are present.
+
+/// FRAME_MARKER is the first byte of every frame.
+pub const FRAME_MARKER: u8 = 0xfb;
+
+/// KEY_NOT_FOUND is the error of a response for a key that does not exist.
+pub const KEY_NOT_FOUND: &str = "Key not found";
+
+const FRAME_HEADER_LEN: usize = 5;
+
+const COMMAND_GET: u8 = 1;
+const COMMAND_SET: u8 = 2;
+const COMMAND_REMOVE: u8 = 3;
+const COMMAND_BATCH: u8 = 4;
+const COMMAND_SET_WITH_TTL: u8 = 5;
+const COMMAND_TTL: u8 = 6;
+const COMMAND_PERSIST: u8 = 7;
+const COMMAND_SET_IF: u8 = 8;
+const COMMAND_MULTI: u8 = 9;
+const COMMAND_EXEC: u8 = 10;
+const COMMAND_DISCARD: u8 = 11;
+
+const CONDITION_ABSENT: u8 = 1;
+const CONDITION_PRESENT: u8 = 2;
+const CONDITION_EQUALS: u8 = 3;
+
+const BATCH_OP_PUT: u8 = 1;
+const BATCH_OP_DELETE: u8 = 2;
+
+/// Response answers a `Command` in a frame, and a request of kvs_protocol as
+/// JSON.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Response {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub error: Option<String>,
     pub result: String,
+    /// The value of the key for `Command::Get`, which may not be UTF-8.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub value: Option<Vec<u8>>,
+}
+
+/// Command is a request sent as a single frame, and answered with a
+/// `Response`. Unlike the requests of kvs_protocol, its keys and values are
+/// bytes, which the frames carry as they are, including newlines and NULs.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Returns the value of the key in `Response::value`.
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    Batch(WriteBatch),
+    /// Sets the value of the key, which expires after `ttl_ms` milliseconds.
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl_ms: u64,
+    },
+    /// Returns the remaining time to live of the key in milliseconds, or -1 if
+    /// the key never expires.
+    Ttl {
+        key: Vec<u8>,
+    },
+    /// Drops the expiry of the key.
+    Persist {
+        key: Vec<u8>,
+    },
+    /// Sets the value of the key only if the key satisfies the condition;
+    /// answered with the "Condition failed" error otherwise.
+    SetIf {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        condition: Condition,
+    },
+    /// Begins a transaction on the connection. The `Get`, `Set` and `Remove`
+    /// commands following it on the same connection run in the transaction,
+    /// until it is ended by `Exec` or `Discard`; other commands are refused.
+    /// Closing the connection discards the transaction.
+    Multi,
+    /// Commits the transaction; answered with the "Transaction conflict" error
+    /// if a key read in the transaction is changed by another client.
+    Exec,
+    /// Aborts the transaction.
+    Discard,
+}
+
+/// Writes the command as a frame.
+pub fn write_command<W: Write>(writer: &mut W, cmd: &Command) -> Result<()> {
+    let mut buf = Vec::new();
+    match cmd {
+        Command::Get { key } => {
+            buf.push(COMMAND_GET);
+            put_bytes(&mut buf, key);
+        }
+        Command::Set { key, value } => {
+            buf.push(COMMAND_SET);
+            put_bytes(&mut buf, key);
+            put_bytes(&mut buf, value);
+        }
+        Command::Remove { key } => {
+            buf.push(COMMAND_REMOVE);
+            put_bytes(&mut buf, key);
+        }
+        Command::Batch(batch) => {
+            buf.push(COMMAND_BATCH);
+            buf.extend_from_slice(&(batch.len() as u32).to_le_bytes());
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Put { key, value } => {
+                        buf.push(BATCH_OP_PUT);
+                        put_bytes(&mut buf, key);
+                        put_bytes(&mut buf, value);
+                    }
+                    BatchOp::Delete { key } => {
+                        buf.push(BATCH_OP_DELETE);
+                        put_bytes(&mut buf, key);
+                    }
+                }
+            }
+        }
+        Command::SetWithTtl { key, value, ttl_ms } => {
+            buf.push(COMMAND_SET_WITH_TTL);
+            put_bytes(&mut buf, key);
+            put_bytes(&mut buf, value);
+            buf.extend_from_slice(&ttl_ms.to_le_bytes());
+        }
+        Command::Ttl { key } => {
+            buf.push(COMMAND_TTL);
+            put_bytes(&mut buf, key);
+        }
+        Command::Persist { key } => {
+            buf.push(COMMAND_PERSIST);
+            put_bytes(&mut buf, key);
+        }
+        Command::SetIf {
+            key,
+            value,
+            condition,
+        } => {
+            buf.push(COMMAND_SET_IF);
+            put_bytes(&mut buf, key);
+            put_bytes(&mut buf, value);
+            match condition {
+                Condition::Absent => buf.push(CONDITION_ABSENT),
+                Condition::Present => buf.push(CONDITION_PRESENT),
+                Condition::Equals(expected) => {
+                    buf.push(CONDITION_EQUALS);
+                    put_bytes(&mut buf, expected);
+                }
+            }
+        }
+        Command::Multi => buf.push(COMMAND_MULTI),
+        Command::Exec => buf.push(COMMAND_EXEC),
+        Command::Discard => buf.push(COMMAND_DISCARD),
+    }
+    write_frame(writer, &buf)
+}
+
+/// Reads a command from its frame, or returns None if the connection is closed
+/// before the frame. A frame that cannot be decoded fails with
+/// `KvsError::Parser`, leaving the reader at the next frame.
+pub fn read_command<R: Read>(reader: &mut R) -> Result<Option<Command>> {
+    let payload = match read_frame(reader)? {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+
+    let mut buf = Decoder(&payload[..]);
+    let cmd = match buf.u8()? {
+        COMMAND_GET => Command::Get { key: buf.bytes()? },
+        COMMAND_SET => Command::Set {
+            key: buf.bytes()?,
+            value: buf.bytes()?,
+        },
+        COMMAND_REMOVE => Command::Remove { key: buf.bytes()? },
+        COMMAND_BATCH => {
+            let mut batch = WriteBatch::new();
+            for _ in 0..buf.u32()? {
+                match buf.u8()? {
+                    BATCH_OP_PUT => batch.put(buf.bytes()?, buf.bytes()?),
+                    BATCH_OP_DELETE => batch.delete(buf.bytes()?),
+                    t => return Err(malformed(format!("unknown batch write type {}", t))),
+                };
+            }
+            Command::Batch(batch)
+        }
+        COMMAND_SET_WITH_TTL => Command::SetWithTtl {
+            key: buf.bytes()?,
+            value: buf.bytes()?,
+            ttl_ms: buf.u64()?,
+        },
+        COMMAND_TTL => Command::Ttl { key: buf.bytes()? },
+        COMMAND_PERSIST => Command::Persist { key: buf.bytes()? },
+        COMMAND_SET_IF => Command::SetIf {
+            key: buf.bytes()?,
+            value: buf.bytes()?,
+            condition: match buf.u8()? {
+                CONDITION_ABSENT => Condition::Absent,
+                CONDITION_PRESENT => Condition::Present,
+                CONDITION_EQUALS => Condition::Equals(buf.bytes()?),
+                t => return Err(malformed(format!("unknown condition type {}", t))),
+            },
+        },
+        COMMAND_MULTI => Command::Multi,
+        COMMAND_EXEC => Command::Exec,
+        COMMAND_DISCARD => Command::Discard,
+        t => return Err(malformed(format!("unknown command type {}", t))),
+    };
+    buf.finish()?;
+    Ok(Some(cmd))
+}
+
+/// Writes the response as a frame.
+pub fn write_response<W: Write>(writer: &mut W, resp: &Response) -> Result<()> {
+    let mut buf = Vec::new();
+    put_optional_bytes(&mut buf, resp.error.as_ref().map(|e| e.as_bytes()));
+    put_bytes(&mut buf, resp.result.as_bytes());
+    put_optional_bytes(&mut buf, resp.value.as_deref());
+    write_frame(writer, &buf)
+}
+
+/// Reads a response from its frame.
+pub fn read_response<R: Read>(reader: &mut R) -> Result<Response> {
+    let payload = match read_frame(reader)? {
+        Some(payload) => payload,
+        None => return Err(KvsError::TCP("connection closed by the server".to_string())),
+    };
+
+    let mut buf = Decoder(&payload[..]);
+    let resp = Response {
+        error: buf.optional_bytes()?.map(String::from_utf8).transpose()?,
+        result: String::from_utf8(buf.bytes()?)?,
+        value: buf.optional_bytes()?,
+    };
+    buf.finish()?;
+    Ok(resp)
+}
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    header[0] = FRAME_MARKER;
+    header[1..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// reads the payload of a frame, or returns None if the reader ends before it.
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    if header[0] != FRAME_MARKER {
+        return Err(KvsError::TCP(format!(
+            "expected a frame, got byte {:#x}",
+            header[0]
+        )));
+    }
+
+    // the payload is read as it arrives rather than allocated up front, since
+    // its length is not trusted.
+    let len = u32::from_le_bytes(header[1..].try_into().unwrap()) as u64;
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(KvsError::TCP(
+            "connection closed within a frame".to_string(),
+        ));
+    }
+    Ok(Some(payload))
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_optional_bytes(buf: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            buf.push(1);
+            put_bytes(buf, bytes);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn malformed(msg: String) -> KvsError {
+    KvsError::Parser(format!("frame: {}", msg))
+}
+
+// Decoder reads the fields of a payload from its beginning.
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(malformed("payload ends within a field".to_string()));
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn optional_bytes(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => self.bytes().map(Some),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        if !self.0.is_empty() {
+            return Err(malformed(format!(
+                "{} bytes after the fields",
+                self.0.len()
+            )));
+        }
+        Ok(())
+    }
 }
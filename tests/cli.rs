@@ -2,6 +2,7 @@ use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use rand::Rng;
 use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -213,6 +214,32 @@ fn cli_wrong_engine() {
     }
 }
 
+// `kvs-server` should infer the engine of a data directory without the engine
+// marker file from the files in it, and refuse to open it with the other engine.
+#[test]
+fn cli_wrong_engine_without_marker() {
+    for (engine, other) in [("sled", "kvs"), ("kvs", "sled")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(&["--engine", engine, "--addr", "127.0.0.1:4017"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+        fs::remove_file(temp_dir.path().join("engine")).unwrap();
+
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(&["--engine", other, "--addr", "127.0.0.1:4018"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+        assert!(!temp_dir.path().join("engine").exists());
+    }
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -472,6 +499,468 @@ fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
 
+fn cli_batch(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["batch", "set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "batch", "set", "key2", "value2", "rm", "key1", "set", "key3", "value3", "--addr", addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\n");
+
+    // incomplete writes should be rejected by the client.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["batch", "set", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_batch_kvs_engine() {
+    cli_batch("kvs", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_batch_sled_engine() {
+    cli_batch("sled", "127.0.0.1:4007");
+}
+
+fn cli_ttl(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--ttl", "100", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("100\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["persist", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["ttl", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["persist", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_ttl_kvs_engine() {
+    cli_ttl("kvs", "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_ttl_sled_engine() {
+    cli_ttl("sled", "127.0.0.1:4009");
+}
+
+fn cli_set_if(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--if-absent", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value2", "--if-absent", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "key1",
+            "value2",
+            "--if-equals",
+            "value1",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--if-present", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_set_if_kvs_engine() {
+    cli_set_if("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn cli_set_if_sled_engine() {
+    cli_set_if("sled", "127.0.0.1:4011");
+}
+
+#[test]
+fn cli_binary_data() {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "multi\nline key",
+            "multi\nline\tvalue",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "multi\nline key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("multi\nline\tvalue\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "multi", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client get` should print "Key not found" only for a key that does not
+// exist, and fail with the error of the server otherwise.
+#[test]
+fn cli_get_error() {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "original-value", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // the value is overwritten in place, so its record fails the checksum.
+    let log_file = fs::read_dir(&temp_dir)
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.extension() == Some("log".as_ref()))
+        .expect("no log file in the directory");
+    let offset = fs::read(&log_file)
+        .unwrap()
+        .windows(b"original-value".len())
+        .position(|w| w == b"original-value")
+        .expect("value is not in the log file");
+    let mut file = fs::OpenOptions::new().write(true).open(&log_file).unwrap();
+    file.seek(SeekFrom::Start(offset as u64)).unwrap();
+    file.write_all(b"O").unwrap();
+    file.sync_all().unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("corrupted record"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_multi() {
+    let addr = "127.0.0.1:4013";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["multi", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get key1\nset key2 value 2\nrm key1\nget key2\nget key1\nexec\n")
+        .assert()
+        .success()
+        .stdout("value1\nvalue 2\nKey not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["multi", "--addr", addr])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key3 value3\ndiscard\n")
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value 2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+// sled cannot serve its data directory read-only, so the server refuses to
+// start, without touching the directory.
+#[test]
+fn cli_read_only_sled_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--engine",
+            "sled",
+            "--addr",
+            "127.0.0.1:4015",
+            "--read-only",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Read-only mode is not supported"));
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn cli_config_file() {
+    let addr = "127.0.0.1:4014";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--durability", "always"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let config_path = temp_dir.path().join("config.json");
+    fs::write(
+        &config_path,
+        r#"{"read_only": true, "reader_cache_size": 4}"#,
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--config"])
+        .arg(&config_path)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // unknown fields of the config file are rejected.
+    fs::write(&config_path, r#"{"segment_size": 1024}"#).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr, "--config"])
+        .arg(&config_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 fn retry_with_backoff<F, R, E>(
     mut f: F,
     max_retries: u32,
@@ -1,8 +1,11 @@
-use kvs::server::KvServer;
-use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CacheStats, CachedEngine, Condition, Durability, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    Result, SledKvsEngine, WriteBatch,
+};
+use std::io::{Seek, SeekFrom, Write};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -88,16 +91,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    // TODO: compaction runs on server - not in KvStore which causes
-    // this test case to fail as it checks compaction on KvStore.
-    // TODO: compaction thread is not triggered
-    let s = KvServer::new_with_path(temp_dir.path().into());
-    let thread_pool = NaiveThreadPool::new(1).unwrap();
-    let store = s.engine.clone();
-    thread::spawn(move || {
-        s.start("127.0.0.1:4004".to_string(), thread_pool);
-    });
-    // let store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -220,3 +214,1579 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Compaction should leave a hint file next to the compacted log, and the store
+// should be rebuilt correctly from the hints as well as without them.
+#[test]
+fn compaction_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let hint_files = || -> Vec<std::path::PathBuf> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path().to_owned())
+            .filter(|p| p.extension() == Some("hint".as_ref()))
+            .collect()
+    };
+
+    // the keys written during the compaction are dead in the merged log, so a
+    // key written once keeps the merged log from being empty.
+    store.set("cold", "value")?;
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if hint_files().is_empty() {
+            continue;
+        }
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter))
+            );
+        }
+        assert_eq!(store.get("cold")?, Some("value".to_owned()));
+        drop(store);
+
+        // hints failing their checksums must be ignored and the logs replayed.
+        for hint_file in hint_files() {
+            let mut content = std::fs::read(&hint_file)?;
+            let last = content.len() - 1;
+            content[last] ^= 0xff;
+            std::fs::write(hint_file, content)?;
+        }
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter))
+            );
+        }
+        return Ok(());
+    }
+
+    panic!("No hint file detected");
+}
+
+// Only the log files holding mostly dead data are compacted; the log holding the
+// cold keys is left alone, and the Remove records shadowing it are kept.
+#[test]
+fn incremental_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let files = |extension: &str| -> Vec<std::path::PathBuf> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path().to_owned())
+            .filter(|p| p.extension() == Some(extension.as_ref()))
+            .collect()
+    };
+    // overwrites the hot keys until a compaction removes a merged log file.
+    let compact = |store: &KvStore| -> Result<()> {
+        let logs = files("log");
+        for iter in 0..10000 {
+            for key_id in 0..100 {
+                store.set(format!("hot{}", key_id), format!("{:0100}", iter))?;
+            }
+            if logs.iter().any(|log| !log.exists()) {
+                return Ok(());
+            }
+        }
+        panic!("No compaction detected");
+    };
+
+    let cold_value = "cold".repeat(256);
+    for key_id in 0..1000 {
+        store.set(format!("cold{}", key_id), cold_value.clone())?;
+    }
+    store.set("removed", "value")?;
+    compact(&store)?;
+    let cold_log = files("hint")[0].with_extension("log");
+    let cold_log_len = std::fs::metadata(&cold_log)?.len();
+
+    store.remove("removed")?;
+    compact(&store)?;
+    compact(&store)?;
+    assert_eq!(std::fs::metadata(&cold_log)?.len(), cold_log_len);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("removed")?, None);
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("cold{}", key_id))?,
+            Some(cold_value.clone())
+        );
+    }
+    drop(store);
+
+    // the Remove records are kept in the logs as well, not only in the hints.
+    for hint_file in files("hint") {
+        std::fs::remove_file(hint_file)?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("removed")?, None);
+    assert_eq!(store.get("cold0")?, Some(cold_value));
+
+    Ok(())
+}
+
+// The writes made while the compaction merges the logs win over the records
+// copied by the compaction, in memory and after the logs are replayed.
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..200 {
+                for key_id in 0..100 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.set(key, format!("{:0100}", iter)).unwrap();
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert!(
+        !temp_dir.path().join("1.log").exists(),
+        "No compaction detected"
+    );
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..100 {
+                let key = format!("key{}-{}", thread_id, key_id);
+                assert_eq!(store.get(key)?, Some(format!("{:0100}", 199)));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)
+}
+
+// The files that are not in the MANIFEST are leftovers of an unfinished
+// compaction; they are removed on open instead of being replayed.
+#[test]
+fn manifest_orphans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    drop(store);
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    other.set("orphan", "value")?;
+    drop(other);
+
+    let dir = temp_dir.path();
+    std::fs::copy(other_dir.path().join("1.log"), dir.join("100.log"))?;
+    std::fs::write(dir.join("100.hint"), "{\"log_len\":0}\n")?;
+    std::fs::write(dir.join("101.log.compacting"), "partial")?;
+    std::fs::write(dir.join("MANIFEST.tmp"), "partial")?;
+    std::fs::write(dir.join("engine"), "kvs")?;
+
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("orphan")?, None);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    for orphan in ["100.log", "100.hint", "101.log.compacting", "MANIFEST.tmp"] {
+        assert!(!dir.join(orphan).exists(), "{} is not removed", orphan);
+    }
+    assert!(dir.join("engine").exists());
+    store.set("key2", "value2")?;
+    drop(store);
+
+    // the stores without a MANIFEST replay every log file, and get a MANIFEST.
+    std::fs::remove_file(dir.join("MANIFEST"))?;
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert!(dir.join("MANIFEST").exists());
+
+    Ok(())
+}
+
+// The active log file should be sealed once it grows past the segment size,
+// and the writes continue in a new one.
+#[test]
+fn segment_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let max_segment_size = 4 * 1024;
+    let options = KvStoreOptions::new().max_segment_size(max_segment_size);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    let value = "v".repeat(100);
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+
+    let log_sizes: Vec<u64> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension() == Some("log".as_ref()))
+        .map(|e| e.metadata().unwrap().len())
+        .collect();
+    assert!(log_sizes.len() > 10, "only {} log files", log_sizes.len());
+    // a log file is sealed after the write that takes it past the limit.
+    for size in log_sizes {
+        assert!(size < max_segment_size + 256, "log file of {} bytes", size);
+    }
+
+    drop(store);
+    // the keys are spread over more log files than a reader keeps open.
+    let options = KvStoreOptions::new().reader_cache_size(2);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..500 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+
+    Ok(())
+}
+
+// The log files are replayed in parallel on open, and the writes of the later
+// log files should win over the earlier ones.
+#[test]
+fn replay_in_log_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(4 * 1024)
+        .compaction_threshold(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    for round in 0..5 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, round),
+            )?;
+        }
+        for key_id in (round..100).step_by(7) {
+            store.remove(format!("key{}", key_id))?;
+        }
+    }
+    // values larger than the chunks the log files are read in.
+    let large = "v".repeat(300 * 1024);
+    store.set("large", large.clone())?;
+    let mut batch = WriteBatch::new();
+    batch.put("large", large.repeat(2));
+    batch.put("key0", "batched");
+    store.write_batch(batch)?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 1..100 {
+        let expected = (key_id % 7 != 4).then(|| format!("value{}-4", key_id));
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+    assert_eq!(store.get("key0")?, Some("batched".to_owned()));
+    assert_eq!(store.get("large")?, Some(large.repeat(2)));
+
+    Ok(())
+}
+
+// The clones of a store should share the open log files, and read while the
+// compaction merges and removes them.
+#[test]
+fn concurrent_reads_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(16 * 1024)
+        .compaction_threshold(32 * 1024)
+        .reader_cache_size(4);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}-0", key_id))?;
+    }
+
+    let barrier = Arc::new(Barrier::new(9));
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        let barrier = Arc::clone(&barrier);
+        handles.push(thread::spawn(move || -> Result<()> {
+            barrier.wait();
+            for round in 0..20 {
+                for key_id in 0..100 {
+                    let value = store.get(format!("key{}", key_id))?.expect("key is lost");
+                    assert!(value.starts_with(&format!("value{}-", key_id)));
+                }
+                if round % 5 == 0 {
+                    assert_eq!(
+                        store.scan("key".to_owned().."kez".to_owned(), 1000)?.len(),
+                        100
+                    );
+                }
+            }
+            Ok(())
+        }));
+    }
+
+    barrier.wait();
+    for iter in 1..50 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}-49", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Returns the path of the log file with the highest index in the directory.
+fn last_log_file(dir: &std::path::Path) -> std::path::PathBuf {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path().to_owned())
+        .filter(|p| p.extension() == Some("log".as_ref()))
+        .max_by_key(|p| {
+            p.file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u32>().ok())
+        })
+        .expect("no log file in the directory")
+}
+
+// A torn write at the tail of the last log should be truncated on open,
+// keeping the records before it.
+#[test]
+fn torn_write_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_file = last_log_file(temp_dir.path());
+    let len = std::fs::metadata(&log_file)?.len();
+    // cut the last record in half.
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 5)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let torn_write = store.torn_write().expect("no torn write is reported");
+    assert_eq!(torn_write.records, 1);
+    assert_eq!(torn_write.offset + torn_write.bytes, len - 5);
+    assert_eq!(std::fs::metadata(&log_file)?.len(), torn_write.offset);
+
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.torn_write(), None);
+
+    Ok(())
+}
+
+// A damaged record followed by valid ones in the last log is not a torn write;
+// opening the store should fail with `KvsError::Corruption` and keep the log.
+#[test]
+fn active_log_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "first-value")?;
+    store.set("key2", "value2")?;
+    store.set("key3", "value3")?;
+    drop(store);
+
+    let log_file = last_log_file(temp_dir.path());
+    let mut content = std::fs::read(&log_file)?;
+    let offset = content
+        .windows(b"first-value".len())
+        .position(|w| w == b"first-value")
+        .expect("value is not in the last log file");
+    content[offset] ^= 0xff;
+    std::fs::write(&log_file, &content)?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption(..))
+    ));
+    assert_eq!(std::fs::read(&log_file)?, content);
+
+    Ok(())
+}
+
+// A store opened read-only should serve the reads, fail the writes, and leave
+// the files on the disk as they are, even a torn write.
+#[test]
+fn read_only_mode() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    drop(store);
+
+    let log_file = last_log_file(temp_dir.path());
+    let len = std::fs::metadata(&log_file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 5)?;
+
+    let files = |dir: &std::path::Path| -> Vec<(std::path::PathBuf, u64)> {
+        let mut files: Vec<_> = WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| (e.path().to_owned(), e.metadata().unwrap().len()))
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files(temp_dir.path());
+
+    let options = KvStoreOptions::new().read_only(true);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(
+        store.torn_write().map(|torn_write| torn_write.records),
+        Some(1)
+    );
+    assert!(matches!(
+        store.set("key3", "value3"),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.remove("key1"), Err(KvsError::ReadOnly)));
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("key1")?, Some("value1".to_owned()));
+    txn.commit()?;
+    store.sync()?;
+    drop(store);
+    assert_eq!(files(temp_dir.path()), before);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2", "value3")?;
+    assert_eq!(store.get("key2")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A record of a sealed log file, which is read through its mapping, should
+// still be verified against its checksum.
+#[test]
+fn sealed_segment_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0", "sealed-value")?;
+    for key_id in 1..100 {
+        store.set(format!("key{}", key_id), "v".repeat(50))?;
+    }
+    assert_eq!(store.get("key0")?, Some("sealed-value".to_owned()));
+
+    let log_file = temp_dir.path().join("1.log");
+    let content = std::fs::read(&log_file)?;
+    let offset = content
+        .windows(b"sealed-value".len())
+        .position(|w| w == b"sealed-value")
+        .expect("value is not in the first log file");
+    // the byte is overwritten in place, since the file is mapped.
+    let mut file = std::fs::OpenOptions::new().write(true).open(&log_file)?;
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.write_all(b"S")?;
+    file.sync_all()?;
+
+    assert!(matches!(store.get("key0"), Err(KvsError::Corruption(1, _))));
+    assert_eq!(store.get("key99")?, Some("v".repeat(50)));
+
+    Ok(())
+}
+
+// Options out of their valid range should be rejected on open.
+#[test]
+fn invalid_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for options in [
+        KvStoreOptions::new().garbage_ratio(0.0),
+        KvStoreOptions::new().garbage_ratio(1.5),
+        KvStoreOptions::new().max_compaction_segments(0),
+        KvStoreOptions::new().max_segment_size(0),
+        KvStoreOptions::new().reader_cache_size(0),
+        KvStoreOptions::new().durability(Durability::Interval(Duration::ZERO)),
+    ] {
+        assert!(matches!(
+            KvStore::open_with(temp_dir.path(), options),
+            Err(KvsError::InvalidOption(_))
+        ));
+    }
+    assert!(KvStore::open_with(temp_dir.path(), KvStoreOptions::new().garbage_ratio(1.0)).is_ok());
+
+    Ok(())
+}
+
+// Reading a record whose bytes do not match its checksum should fail with
+// `KvsError::Corruption`.
+#[test]
+fn get_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let log_file = last_log_file(temp_dir.path());
+    let mut content = std::fs::read(&log_file)?;
+    let last = content.len() - 3;
+    content[last] ^= 0xff;
+    std::fs::write(&log_file, content)?;
+
+    match store.get("key1".to_owned()) {
+        Err(KvsError::Corruption(..)) => Ok(()),
+        other => panic!("expected a corruption error, got {:?}", other),
+    }
+}
+
+// Logs written in the legacy text format should still be loaded.
+#[test]
+fn load_legacy_text_log() -> Result<()> {
+    use kvs_protocol::{request::Request, serializer::serialize};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy_log: String = [
+        Request::Set {
+            key: "key1".to_owned(),
+            val: "value1".to_owned(),
+        },
+        Request::Set {
+            key: "key2".to_owned(),
+            val: "value2".to_owned(),
+        },
+        Request::Rm {
+            key: "key2".to_owned(),
+        },
+    ]
+    .iter()
+    .map(serialize)
+    .collect();
+    std::fs::write(temp_dir.path().join("1.log"), legacy_log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Writes should be persisted with every durability mode.
+#[test]
+fn durability_modes() -> Result<()> {
+    for durability in [
+        Durability::Always,
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::Never,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_durability(temp_dir.path(), durability)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        store.sync()?;
+
+        drop(store);
+        let store = KvStore::open_with_durability(temp_dir.path(), durability)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Concurrent writes synced on every write should all be persisted, and the
+// concurrent removes of the same key should succeed only once.
+#[test]
+fn concurrent_write_with_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_durability(temp_dir.path(), Durability::Always)?;
+    store.set("shared".to_owned(), "value".to_owned())?;
+
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+                store.remove("shared".to_owned()).is_ok()
+            })
+        })
+        .collect();
+    let removed = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|removed| *removed)
+        .count();
+    assert_eq!(removed, 1);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("shared".to_owned())?, None);
+
+    Ok(())
+}
+
+// Runs the engine-agnostic test `$test` against a KvStore and a SledKvsEngine,
+// each in a temporary directory of its own. The engines are wrapped by `$wrap`
+// first, if it is given.
+macro_rules! for_each_engine {
+    ($test:ident) => {
+        for_each_engine!($test, |engine| engine)
+    };
+    ($test:ident, $wrap:expr) => {{
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        $test(($wrap)(KvStore::open(temp_dir.path())?))?;
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        $test(($wrap)(SledKvsEngine::new(sled::open(temp_dir.path())?)))
+    }};
+}
+
+fn scan_in_key_order<E: KvsEngine>(engine: E) -> Result<()> {
+    for i in (0..10).rev() {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.remove("key5".to_owned())?;
+
+    let pairs = engine.scan("key3".to_owned().."key7".to_owned(), usize::MAX)?;
+    let expected: Vec<(String, String)> = [3, 4, 6]
+        .iter()
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    let pairs = engine.scan(.., 2)?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key0".to_owned(), "value0".to_owned()),
+            ("key1".to_owned(), "value1".to_owned())
+        ]
+    );
+
+    Ok(())
+}
+
+// Should return the pairs in a range in key order.
+#[test]
+fn scan_range() -> Result<()> {
+    for_each_engine!(scan_in_key_order)
+}
+
+fn write_batch_atomically<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key2".to_owned(), "value2".to_owned())
+        .put("key3".to_owned(), "value3".to_owned())
+        .delete("key1".to_owned())
+        .delete("missing".to_owned())
+        .put("key3".to_owned(), "value4".to_owned());
+    engine.write_batch(batch)?;
+
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should apply all writes of a batch, and keep them after reopening.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_atomically(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_atomically(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// A batch torn by a crash should be discarded as a whole.
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key2".to_owned(), "value2".to_owned())
+        .delete("key1".to_owned())
+        .put("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // cut the last write of the batch, leaving the first ones intact.
+    let log_file = last_log_file(temp_dir.path());
+    let len = std::fs::metadata(&log_file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+fn expire_keys<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    let ttl = engine.ttl("key1".to_owned())?.expect("key1 has a ttl");
+    assert!(ttl <= Duration::from_millis(200));
+    assert_eq!(engine.ttl("key4".to_owned())?, None);
+
+    engine.persist("key2".to_owned())?;
+    assert_eq!(engine.ttl("key2".to_owned())?, None);
+    // setting a key again drops its expiry as well.
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.ttl("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        engine.persist("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    let keys: Vec<String> = engine
+        .scan(.., usize::MAX)?
+        .into_iter()
+        .map(|p| p.0)
+        .collect();
+    assert_eq!(keys, vec!["key2", "key3", "key4"]);
+
+    Ok(())
+}
+
+#[test]
+fn ttl() -> Result<()> {
+    for_each_engine!(expire_keys)
+}
+
+#[test]
+fn ttl_after_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(300));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    let ttl = store.ttl("long".to_owned())?.expect("long has a ttl");
+    assert!(ttl > Duration::from_secs(3500));
+
+    Ok(())
+}
+
+// Expired keys get tombstones in the log without being touched.
+#[test]
+fn sweep_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    let log_file = last_log_file(temp_dir.path());
+    let len = std::fs::metadata(&log_file)?.len();
+
+    thread::sleep(Duration::from_millis(1500));
+    assert!(std::fs::metadata(&log_file)?.len() > len);
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compaction drops the records of the expired keys instead of copying them into
+// the merged log.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("expiring", "expired-value", Duration::from_millis(10))?;
+    // keeps the merged log from being empty, see compaction_hint_files.
+    store.set("cold", "value")?;
+    thread::sleep(Duration::from_millis(20));
+
+    let compacted = |dir: &std::path::Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .any(|e| e.path().extension() == Some("hint".as_ref()))
+    };
+    for iter in 0..1000 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("{}", iter))?;
+        }
+        if compacted(temp_dir.path()) {
+            break;
+        }
+    }
+    assert!(compacted(temp_dir.path()));
+    drop(store);
+
+    for entry in WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if entry.path().extension() != Some("log".as_ref()) {
+            continue;
+        }
+        let content = std::fs::read(entry.path())?;
+        assert!(
+            !content.windows(13).any(|w| w == b"expired-value"),
+            "{:?} holds the expired value",
+            entry.path()
+        );
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("expiring")?, None);
+    assert!(matches!(store.ttl("expiring"), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("cold")?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// The tombstones written by the sweeper seal the active log once it is full,
+// the same as the writes.
+#[test]
+fn sweep_seals_full_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..200 {
+        store.set_with_ttl(format!("key{}", i), "value", Duration::from_millis(100))?;
+    }
+    let log_file = last_log_file(temp_dir.path());
+
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(store.get("key0")?, None);
+    assert_ne!(last_log_file(temp_dir.path()), log_file);
+
+    Ok(())
+}
+
+fn conditional_writes<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set_if("key1".to_owned(), "value1".to_owned(), Condition::Absent)?;
+    assert!(matches!(
+        engine.set_if("key1".to_owned(), "value2".to_owned(), Condition::Absent),
+        Err(KvsError::ConditionFailed)
+    ));
+    assert!(matches!(
+        engine.set_if("key2".to_owned(), "value2".to_owned(), Condition::Present),
+        Err(KvsError::ConditionFailed)
+    ));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    engine.set_if("key1".to_owned(), "value2".to_owned(), Condition::Present)?;
+    assert!(matches!(
+        engine.set_if(
+            "key1".to_owned(),
+            "value3".to_owned(),
+            Condition::Equals(b"value1".to_vec())
+        ),
+        Err(KvsError::ConditionFailed)
+    ));
+    engine.set_if(
+        "key1".to_owned(),
+        "value3".to_owned(),
+        Condition::Equals(b"value2".to_vec()),
+    )?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    // an expired key does not exist.
+    engine.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    thread::sleep(Duration::from_millis(200));
+    engine.set_if("key3".to_owned(), "value4".to_owned(), Condition::Absent)?;
+    assert_eq!(engine.ttl("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn set_if() -> Result<()> {
+    for_each_engine!(conditional_writes)
+}
+
+// Every increment retried with compare-and-swap should land exactly once.
+fn concurrent_increments<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("counter".to_owned(), "0".to_owned())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let engine = engine.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..50 {
+                loop {
+                    let current = engine.get("counter".to_owned()).unwrap().unwrap();
+                    let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                    match engine.set_if(
+                        "counter".to_owned(),
+                        next,
+                        Condition::Equals(current.into_bytes()),
+                    ) {
+                        Ok(()) => break,
+                        Err(KvsError::ConditionFailed) => continue,
+                        Err(e) => panic!("unexpected error: {}", e),
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(engine.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    for_each_engine!(concurrent_increments)
+}
+
+// The short keys are stored inline in the key_dir and the long ones on their
+// own; both should be found and scanned in key order.
+#[test]
+fn short_and_long_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let keys: Vec<Vec<u8>> = (1..=48).map(|len| vec![b'k'; len]).collect();
+    for key in keys.iter().rev() {
+        store.set(key.clone(), key.len().to_string())?;
+    }
+    store.set_with_ttl(b"e".to_vec(), "expiring", Duration::from_secs(60))?;
+    store.remove(b"e".to_vec())?;
+
+    for store in [store.clone(), KvStore::open(temp_dir.path())?] {
+        for key in &keys {
+            assert_eq!(store.get(key.clone())?, Some(key.len().to_string()));
+        }
+        let scanned: Vec<Vec<u8>> = store
+            .scan_bytes(b"k".to_vec()..vec![b'k'; 40], usize::MAX)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(scanned, keys[..39].to_vec());
+        assert_eq!(store.get(b"e".to_vec())?, None);
+    }
+
+    Ok(())
+}
+
+// Readers should always see a whole position of a key that is overwritten
+// concurrently, never a mix of two.
+#[test]
+fn concurrent_overwrites() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key", "value0")?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 1..2000 {
+                // values of different lengths at different positions.
+                store
+                    .set("key", format!("value{}", "x".repeat(i % 50)))
+                    .unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..2000 {
+                    let value = store.get("key").unwrap().unwrap();
+                    assert!(value.starts_with("value"), "read {:?}", value);
+                }
+            })
+        })
+        .collect();
+
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    Ok(())
+}
+
+fn binary_keys_and_values<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = b"key\0with\nbytes\xff".to_vec();
+    let value = b"\0\n\r\xfe\xff value".to_vec();
+    engine.set(key.clone(), value.clone())?;
+    engine.set(b"key\0".to_vec(), b"\xff".to_vec())?;
+
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+    // the string API refuses the values that are not UTF-8.
+    assert!(matches!(engine.get(key.clone()), Err(KvsError::Utf8(_))));
+
+    let pairs = engine.scan_bytes(b"key\0".to_vec().., usize::MAX)?;
+    assert_eq!(
+        pairs,
+        vec![(b"key\0".to_vec(), b"\xff".to_vec()), (key.clone(), value)]
+    );
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put(b"\0".to_vec(), b"\0".to_vec())
+        .delete(key.clone());
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get_bytes(b"\0".to_vec())?, Some(b"\0".to_vec()));
+    assert_eq!(engine.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn binary_data() -> Result<()> {
+    for_each_engine!(binary_keys_and_values)
+}
+
+// Binary keys and values survive replaying the log and the compaction.
+#[test]
+fn binary_data_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = |i: u32| [&b"\0\n"[..], &i.to_le_bytes()].concat();
+    let value = |i: u32| [&b"\xff\n"[..], &i.to_be_bytes()].concat();
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(key(0), value(0))?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key(0))?, Some(value(0)));
+
+    // overwrite the same keys until the compaction runs.
+    for _ in 0..200 {
+        for i in 0..1000 {
+            store.set(key(i), value(i))?;
+        }
+        if std::fs::read_dir(temp_dir.path())?
+            .filter_map(|e| e.ok())
+            .any(|e| e.path().extension() == Some("hint".as_ref()))
+        {
+            break;
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get_bytes(key(i))?, Some(value(i)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn snapshot_isolation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let snapshot = store.snapshot();
+    store.set("key0", "updated")?;
+    store.remove("key1")?;
+    store.set("key10", "value10")?;
+
+    assert_eq!(snapshot.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key10")?, None);
+    assert_eq!(snapshot.len(), 10);
+    let pairs = snapshot.iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 10);
+    assert_eq!(pairs[1], (b"key1".to_vec(), b"value1".to_vec()));
+
+    assert_eq!(store.get("key0")?, Some("updated".to_owned()));
+    assert_eq!(store.snapshot().len(), 10);
+
+    Ok(())
+}
+
+// Snapshots taken at different times should each see the keys as of their
+// creation, however many times the keys are written after it, and whichever of
+// them is dropped first.
+#[test]
+fn overlapping_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "first")?;
+    store.set("key2", "first")?;
+
+    let first = store.snapshot();
+    store.set("key1", "second")?;
+    store.remove("key2")?;
+    store.set("key3", "second")?;
+
+    let second = store.snapshot();
+    for i in 0..10 {
+        store.set("key1", format!("{}", i))?;
+        store.set("key2", format!("{}", i))?;
+    }
+    store.remove("key3")?;
+
+    assert_eq!(
+        first.scan_bytes(b"key1".to_vec().., 10)?,
+        vec![
+            (b"key1".to_vec(), b"first".to_vec()),
+            (b"key2".to_vec(), b"first".to_vec())
+        ]
+    );
+    drop(first);
+    assert_eq!(second.get("key1")?, Some("second".to_owned()));
+    assert_eq!(second.get("key2")?, None);
+    assert_eq!(
+        second.scan_bytes(b"key2".to_vec().., 10)?,
+        vec![(b"key3".to_vec(), b"second".to_vec())]
+    );
+    assert_eq!(second.len(), 2);
+    drop(second);
+
+    let third = store.snapshot();
+    assert_eq!(third.get("key1")?, Some("9".to_owned()));
+    assert_eq!(third.get("key3")?, None);
+    assert_eq!(third.len(), 2);
+
+    Ok(())
+}
+
+// Log files referred by a snapshot outlive the compaction until the snapshot is
+// dropped.
+#[test]
+fn snapshot_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // keeps the merged log from being empty, see compaction_hint_files.
+    store.set("cold", "value")?;
+    let snapshot = store.snapshot();
+    let first_log = temp_dir.path().join("1.log");
+
+    // overwrite the keys until the compaction runs.
+    let compacted = |dir: &std::path::Path| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .any(|e| e.path().extension() == Some("hint".as_ref()))
+    };
+    for iter in 0..1000 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("{}", iter))?;
+        }
+        if compacted(temp_dir.path()) {
+            break;
+        }
+    }
+    assert!(compacted(temp_dir.path()));
+    thread::sleep(Duration::from_millis(100));
+
+    assert!(first_log.exists());
+    for i in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    drop(snapshot);
+    assert!(!first_log.exists());
+
+    Ok(())
+}
+
+fn optimistic_transactions<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1", "value1")?;
+    engine.set("key2", "value2")?;
+
+    // the transaction reads its own writes.
+    let mut txn = engine.transaction();
+    assert_eq!(txn.get("key1")?, Some("value1".to_owned()));
+    txn.set("key1", "value3");
+    txn.remove("key2");
+    assert_eq!(txn.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(txn.get("key2")?, None);
+    // nothing is written before the commit.
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    txn.commit()?;
+    assert_eq!(engine.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2")?, None);
+
+    // a key read by the transaction is changed before it commits.
+    let mut txn = engine.transaction();
+    assert_eq!(txn.get("key1")?, Some("value3".to_owned()));
+    txn.set("key3", "value3");
+    engine.set("key1", "value4")?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(engine.get("key3")?, None);
+
+    // a key read as missing is created before the transaction commits.
+    let mut txn = engine.transaction();
+    assert_eq!(txn.get("key2")?, None);
+    txn.set("key1", "value5");
+    engine.set("key2", "value2")?;
+    assert!(matches!(txn.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(engine.get("key1")?, Some("value4".to_owned()));
+
+    // writes to the keys the transaction does not read do not conflict.
+    let mut txn = engine.transaction();
+    assert_eq!(txn.get("key1")?, Some("value4".to_owned()));
+    txn.set("key1", "value5");
+    engine.set("key2", "value6")?;
+    txn.commit()?;
+    assert_eq!(engine.get("key1")?, Some("value5".to_owned()));
+
+    // a dropped transaction is aborted.
+    let mut txn = engine.transaction();
+    txn.set("key1", "value7");
+    drop(txn);
+    assert_eq!(engine.get("key1")?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn transaction() -> Result<()> {
+    for_each_engine!(optimistic_transactions)
+}
+
+// Concurrent transfers between accounts, retried on conflicts, keep the total
+// balance unchanged.
+fn concurrent_transfers<E: KvsEngine>(engine: E) -> Result<()> {
+    for i in 0..4 {
+        engine.set(format!("account{}", i), "100")?;
+    }
+
+    let mut handles = Vec::new();
+    for t in 0..4 {
+        let engine = engine.clone();
+        handles.push(thread::spawn(move || {
+            for n in 0..25 {
+                let (from, to) = (format!("account{}", t), format!("account{}", (t + n) % 4));
+                loop {
+                    let mut txn = engine.transaction();
+                    let balance = |txn: &mut kvs::Transaction<E>, key: &str| {
+                        txn.get(key).unwrap().unwrap().parse::<u64>().unwrap()
+                    };
+                    let (a, b) = (balance(&mut txn, &from), balance(&mut txn, &to));
+                    if from != to {
+                        txn.set(from.clone(), (a - 1).to_string());
+                        txn.set(to.clone(), (b + 1).to_string());
+                    }
+                    match txn.commit() {
+                        Ok(()) => break,
+                        Err(KvsError::TransactionConflict) => continue,
+                        Err(e) => panic!("unexpected error: {}", e),
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut total = 0;
+    for i in 0..4 {
+        total += engine
+            .get(format!("account{}", i))?
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+    }
+    assert_eq!(total, 400);
+    Ok(())
+}
+
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    for_each_engine!(concurrent_transfers)
+}
+
+// Reads through a CachedEngine are served from the cache until the key is
+// written, and the cache stays within its size.
+fn cached_reads<E: KvsEngine>(engine: E) -> Result<()> {
+    let cached = CachedEngine::new(engine, 1024);
+    cached.set("key1", "value1")?;
+    assert_eq!(cached.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(cached.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(cached.get("key2")?, None);
+    assert_eq!(cached.get("key2")?, None);
+    assert_eq!(cached.stats(), CacheStats { hits: 1, misses: 3 });
+
+    // every write invalidates the keys it touches.
+    cached.set("key1", "value2")?;
+    assert_eq!(cached.get("key1")?, Some("value2".to_owned()));
+    cached.remove("key1")?;
+    assert_eq!(cached.get("key1")?, None);
+
+    cached.set("key1", "value3")?;
+    cached.get("key1")?;
+    let mut batch = WriteBatch::new();
+    batch.put("key1", "value4");
+    cached.write_batch(batch)?;
+    assert_eq!(cached.get("key1")?, Some("value4".to_owned()));
+
+    let mut txn = cached.transaction();
+    txn.set("key1", "value5");
+    txn.commit()?;
+    assert_eq!(cached.get("key1")?, Some("value5".to_owned()));
+
+    cached.set_if("key1", "value6", Condition::Equals("value5".into()))?;
+    assert_eq!(cached.get("key1")?, Some("value6".to_owned()));
+
+    // a value cached with an expiry is not served once it expires.
+    cached.set_with_ttl("key3", "value7", Duration::from_millis(200))?;
+    assert_eq!(cached.get("key3")?, Some("value7".to_owned()));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(cached.get("key3")?, None);
+
+    // the least recently used values are evicted to fit the new ones, and a
+    // value larger than the cache is never cached.
+    let value = "v".repeat(200);
+    for i in 0..8 {
+        cached.set(format!("big{}", i), value.clone())?;
+        cached.get(format!("big{}", i))?;
+    }
+    let stats = cached.stats();
+    cached.get("big7")?;
+    cached.get("big0")?;
+    assert_eq!(
+        cached.stats(),
+        CacheStats {
+            hits: stats.hits + 1,
+            misses: stats.misses + 1
+        }
+    );
+
+    cached.set("huge", "v".repeat(2048))?;
+    cached.get("huge")?;
+    let stats = cached.stats();
+    assert_eq!(cached.get("huge")?, Some("v".repeat(2048)));
+    assert_eq!(cached.stats().misses, stats.misses + 1);
+
+    Ok(())
+}
+
+fn cached<E: KvsEngine>(engine: E) -> CachedEngine<E> {
+    CachedEngine::new(engine, 1024)
+}
+
+#[test]
+fn cached_engine() -> Result<()> {
+    for_each_engine!(cached_reads)?;
+    for_each_engine!(expire_keys, cached)?;
+    for_each_engine!(concurrent_transfers, cached)
+}
+
+fn blob_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|path| path.extension() == Some("blob".as_ref()))
+        .collect();
+    files.sort();
+    files
+}
+
+// Values of at least the blob threshold are kept in blob files, and only their
+// positions in the log.
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().blob_threshold(1024);
+    let large = |i: u32| vec![i as u8; 4096];
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), large(i))?;
+    }
+    store.set("small", "value")?;
+    store.set_with_ttl("expiring", large(10), Duration::from_millis(100))?;
+    let mut batch = WriteBatch::new();
+    batch
+        .put("batched", large(11))
+        .put("batched-small", "value");
+    store.write_batch(batch)?;
+
+    assert_eq!(store.get_bytes("key3")?, Some(large(3)));
+    assert_eq!(store.get("small")?, Some("value".to_owned()));
+    assert_eq!(store.get_bytes("batched")?, Some(large(11)));
+    assert_eq!(store.get("batched-small")?, Some("value".to_owned()));
+    assert_eq!(store.get_bytes("expiring")?, Some(large(10)));
+    assert_eq!(blob_files(temp_dir.path()).len(), 1);
+    let log_size = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(log_size < 4096, "log file has {} bytes", log_size);
+
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.get_bytes("expiring")?, None);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for i in 0..10 {
+        assert_eq!(store.get_bytes(format!("key{}", i))?, Some(large(i)));
+    }
+    assert_eq!(store.get_bytes("batched")?, Some(large(11)));
+    assert_eq!(store.get_bytes("expiring")?, None);
+    drop(store);
+
+    // a value is checked against the checksum in its BlobRef record.
+    let blob_file = &blob_files(temp_dir.path())[0];
+    let mut file = std::fs::OpenOptions::new().write(true).open(blob_file)?;
+    file.seek(SeekFrom::Start(100))?;
+    file.write_all(b"garbage")?;
+    drop(file);
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    assert!(matches!(
+        store.get("key0"),
+        Err(KvsError::BlobCorruption(_, _))
+    ));
+    assert_eq!(store.get_bytes("key1")?, Some(large(1)));
+
+    Ok(())
+}
+
+// Overwritten values are reclaimed by the blob GC, and the blob files referred
+// by a snapshot outlive it until the snapshot is dropped.
+#[test]
+fn blob_gc() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || {
+        KvStoreOptions::new()
+            .blob_threshold(1024)
+            .max_segment_size(64 * 1024)
+            .compaction_threshold(64 * 1024)
+    };
+    let large = |i: u32, iter: u32| vec![(i + iter) as u8; 4096];
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), large(i, 0))?;
+    }
+    let snapshot = store.snapshot();
+    let first_blob = temp_dir.path().join("1.blob");
+    assert!(first_blob.exists());
+
+    // 4 MiB of values are written into blob files of 64 KiB, and every
+    // overwritten value is garbage, so most of the blob files are collected.
+    let last = 100;
+    for iter in 1..=last {
+        for i in 0..10 {
+            store.set(format!("key{}", i), large(i, iter))?;
+        }
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert!(first_blob.exists());
+    for i in 0..10 {
+        assert_eq!(snapshot.get_bytes(format!("key{}", i))?, Some(large(i, 0)));
+        assert_eq!(store.get_bytes(format!("key{}", i))?, Some(large(i, last)));
+    }
+
+    drop(snapshot);
+    assert!(!first_blob.exists());
+    let files = blob_files(temp_dir.path());
+    assert!(files.len() < 10, "blob files left: {:?}", files);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for i in 0..10 {
+        assert_eq!(store.get_bytes(format!("key{}", i))?, Some(large(i, last)));
+    }
+
+    Ok(())
+}